@@ -15,18 +15,18 @@ use ch32v103_hal::delay::*;
 #[entry]
 fn main() -> ! {
     let peripherals = Peripherals::take().unwrap();
-    let rcc = peripherals.RCC.constrain();
+    let mut rcc = peripherals.RCC.constrain();
 
-    // let clocks = rcc.cfgr.freeze();
-    // 72MHz not worked for me
-    // let clocks = rcc.cfgr.use_pll((64).mhz(), PllClkSrc::Hsi).freeze();
-    let clocks = rcc.cfgr.use_pll((48).mhz(), PllClkSrc::HsiDiv2).hclk((24).mhz()).freeze();
+    // let clocks = rcc.cfgr.freeze().unwrap();
+    // let clocks = rcc.cfgr.use_pll((72).mhz(), PllClkSrc::Hsi).freeze().unwrap();
+    // let clocks = rcc.cfgr.use_pll((64).mhz(), PllClkSrc::Hsi).freeze().unwrap();
+    let clocks = rcc.cfgr.use_pll((48).mhz(), PllClkSrc::HsiDiv2).hclk((24).mhz()).freeze().unwrap();
 
-    let gpioa = peripherals.GPIOA.split();
+    let gpioa = peripherals.GPIOA.split(&mut rcc.apb2);
     let mut led_r1 = gpioa.pa4.into_push_pull_output();
     let mut led_r2 = gpioa.pa5.into_push_pull_output();
 
-    let gpiob = peripherals.GPIOB.split();
+    let gpiob = peripherals.GPIOB.split(&mut rcc.apb2);
     let mut led1 = gpiob.pb2.into_push_pull_output();
     let mut led2 = gpiob.pb15.into_push_pull_output();
     let mut led3 = gpiob.pb0.into_push_pull_output();
@@ -9,6 +9,7 @@ use ch32v1::ch32v103; // PAC for CH32V103
 use ch32v103_hal::prelude::*;
 use ch32v103_hal::rcc::*;
 use ch32v103_hal::gpio::*;
+use ch32v103_hal::afio::*;
 use ch32v103_hal::serial::*;
 use nb;
 use ch32v103_hal::delay::*;
@@ -16,19 +17,20 @@ use ch32v103_hal::delay::*;
 #[entry]
 fn main() -> ! {
     let peripherals = ch32v103::Peripherals::take().unwrap();
-    let rcc = peripherals.RCC.constrain();
+    let mut rcc = peripherals.RCC.constrain();
+    let mut afio = peripherals.AFIO.constrain(&mut rcc.apb2);
 
-    let clocks = rcc.cfgr.freeze();
+    let clocks = rcc.cfgr.freeze().unwrap();
 
-    let gpioa = peripherals.GPIOA.split();
+    let gpioa = peripherals.GPIOA.split(&mut rcc.apb2);
     let pa9 = gpioa.pa9.into_multiplex_push_pull_output();
     let pa10 = gpioa.pa10.into_floating_input();
 
-    let gpiob = peripherals.GPIOB.split();
+    let gpiob = peripherals.GPIOB.split(&mut rcc.apb2);
     let mut led1 = gpiob.pb2.into_push_pull_output();
     let mut led2 = gpiob.pb15.into_push_pull_output();
 
-    let usart = Serial::usart1(peripherals.USART1, (pa9, pa10), (115200).bps(), &clocks);
+    let usart = Serial::usart1(peripherals.USART1, (pa9, pa10), &mut afio, &mut rcc.apb2, (115200).bps(), &clocks).unwrap();
     let (mut tx, mut rx) = usart.split();
 
     led1.set_high().unwrap();
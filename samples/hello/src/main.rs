@@ -10,6 +10,7 @@ use ch32v1::ch32v103; // PAC for CH32V103
 use ch32v103_hal::prelude::*;
 use ch32v103_hal::rcc::*;
 use ch32v103_hal::gpio::*;
+use ch32v103_hal::afio::*;
 use ch32v103_hal::serial::*;
 use ch32v103_hal::delay::*;
 use embedded_hal::blocking::serial::Write; // Required for bwrite_all
@@ -17,27 +18,28 @@ use embedded_hal::blocking::serial::Write; // Required for bwrite_all
 #[entry]
 fn main() -> ! {
     let peripherals = ch32v103::Peripherals::take().unwrap();
-    let rcc = peripherals.RCC.constrain();
+    let mut rcc = peripherals.RCC.constrain();
+    let mut afio = peripherals.AFIO.constrain(&mut rcc.apb2);
 
-    // let clocks = rcc.cfgr.freeze();
+    // let clocks = rcc.cfgr.freeze().unwrap();
     let clocks = rcc.cfgr
         .use_pll((48).mhz(), PllClkSrc::Hsi)
         .hclk((12).mhz())
         .pclk2((6).mhz())
-        .freeze();
+        .freeze().unwrap();
 
-    // let gpioa = peripherals.GPIOA.split();
+    // let gpioa = peripherals.GPIOA.split(&mut rcc.apb2);
     // let pa9 = gpioa.pa9.into_multiplex_push_pull_output();
     // let pa10 = gpioa.pa10.into_floating_input();
 
-    let gpiob = peripherals.GPIOB.split();
+    let gpiob = peripherals.GPIOB.split(&mut rcc.apb2);
     let mut led1 = gpiob.pb2.into_push_pull_output();
     let mut led2 = gpiob.pb15.into_push_pull_output();
     //  remapped ports
     let pb6 = gpiob.pb6.into_multiplex_push_pull_output();
     let pb7 = gpiob.pb7.into_floating_input();
 
-    let usart = Serial::usart1(peripherals.USART1, (pb6, pb7), (115200).bps(), &clocks);
+    let usart = Serial::usart1(peripherals.USART1, (pb6, pb7), &mut afio, &mut rcc.apb2, (115200).bps(), &clocks).unwrap();
     let (mut tx, _) = usart.split();
     // let mut log = SerialWriter::new(tx);
 
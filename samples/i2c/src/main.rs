@@ -10,6 +10,7 @@ use ch32v1::ch32v103; // PAC for CH32V103
 use ch32v103_hal::prelude::*;
 use ch32v103_hal::rcc::*;
 use ch32v103_hal::gpio::*;
+use ch32v103_hal::afio::*;
 use ch32v103_hal::serial::*;
 use ch32v103_hal::i2c::*;
 use ch32v103_hal::delay::*;
@@ -24,20 +25,21 @@ const READ_STATUS: [u8; 1] = [0x02];
 #[entry]
 fn main() -> ! {
     let peripherals = ch32v103::Peripherals::take().unwrap();
-    let rcc = peripherals.RCC.constrain();
+    let mut rcc = peripherals.RCC.constrain();
+    let mut afio = peripherals.AFIO.constrain(&mut rcc.apb2);
 
-    // let clocks = rcc.cfgr.freeze();
-    let clocks = rcc.cfgr.use_pll((48).mhz(), PllClkSrc::Hsi).hclk((8).mhz()).freeze();
+    // let clocks = rcc.cfgr.freeze().unwrap();
+    let clocks = rcc.cfgr.use_pll((48).mhz(), PllClkSrc::Hsi).hclk((12).mhz()).freeze().unwrap();
 
-    let gpioa = peripherals.GPIOA.split();
+    let gpioa = peripherals.GPIOA.split(&mut rcc.apb2);
     let pa9 = gpioa.pa9.into_multiplex_push_pull_output();
     let pa10 = gpioa.pa10.into_floating_input();
 
-    let usart = Serial::usart1(peripherals.USART1, (pa9, pa10), (115200).bps(), &clocks);
+    let usart = Serial::usart1(peripherals.USART1, (pa9, pa10), &mut afio, &mut rcc.apb2, (115200).bps(), &clocks).unwrap();
     let (tx, _) = usart.split();
     let mut log = SerialWriter::new(tx);
 
-    let gpiob = peripherals.GPIOB.split();
+    let gpiob = peripherals.GPIOB.split(&mut rcc.apb2);
     // LED
     let mut led1 = gpiob.pb2.into_push_pull_output();
     let mut led2 = gpiob.pb15.into_push_pull_output();
@@ -49,7 +51,7 @@ fn main() -> ! {
     let pb9 = gpiob.pb9.into_multiplex_open_drain_output();
 
     // required peripherals.I2C1 to occupy
-    let mut i2c = I2c::i2c1(peripherals.I2C1, (pb6, pb7), I2cMode::Fast, &clocks);
+    let mut i2c = I2c::i2c1(peripherals.I2C1, (pb6, pb7), &mut afio, &mut rcc.apb1, I2cMode::Fast, &clocks).unwrap();
 
     let mut delay = Delay::new(&clocks);
 
@@ -8,16 +8,18 @@ use panic_halt as _;
 use ch32v1::ch32v103; // PAC for CH32V103
 use ch32v103_hal::prelude::*;
 use ch32v103_hal::gpio::*;
+use ch32v103_hal::rcc::*;
 
 #[entry]
 fn main() -> ! {
     let peripherals = ch32v103::Peripherals::take().unwrap();
+    let mut rcc = peripherals.RCC.constrain();
 
-    let gpioa = peripherals.GPIOA.split();
+    let gpioa = peripherals.GPIOA.split(&mut rcc.apb2);
     let mut led_r1 = gpioa.pa4.into_push_pull_output();
     let mut led_r2 = gpioa.pa5.into_push_pull_output();
 
-    let gpiob = peripherals.GPIOB.split();
+    let gpiob = peripherals.GPIOB.split(&mut rcc.apb2);
     let mut led1 = gpiob.pb2.into_push_pull_output();
     let mut led2 = gpiob.pb15.into_push_pull_output();
 
@@ -11,14 +11,13 @@ use panic_halt as _;
 
 // use ch32v1::ch32v103; // PAC for CH32V103
 use ch32v1::ch32v103::Peripherals;
-use ch32v1::ch32v103::{ RCC, TIM1, PFIC };
+use ch32v1::ch32v103::{ TIM1, PFIC };
 use ch32v1::ch32v103::interrupt::Interrupt;
 
 use ch32v103_hal::prelude::*;
 use ch32v103_hal::rcc::*;
 use ch32v103_hal::gpio::*;
 use ch32v103_hal::delay::*;
-use ch32v103_hal::gpio::gpiob::PB15;
 
 // use core::fmt::Write; // required for writeln!
 // use ch32v103_hal::serial::*;
@@ -28,7 +27,7 @@ use ch32v103_hal::gpio::gpiob::PB15;
 // STM32F4 Embedded Rust at the HAL: Timer Interrupts
 // https://apollolabsblog.hashnode.dev/stm32f4-embedded-rust-at-the-hal-timer-interrupts
 
-type LedPin = PB15<Output<PushPull>>;
+type LedPin = Pin<Output<PushPull>>;
 static LED: Mutex<RefCell<Option<LedPin>>> = Mutex::new(RefCell::new(None));
 
 // patch is require for ch32v crate
@@ -66,22 +65,22 @@ fn tim1_up() {
 #[entry]
 fn main() -> ! {
     let peripherals = Peripherals::take().unwrap();
-    let rcc = peripherals.RCC.constrain();
+    let mut rcc = peripherals.RCC.constrain();
 
-    // let clocks = rcc.cfgr.use_lsi().freeze();
-    // 72MHz not worked for me
-    // let clocks = rcc.cfgr.use_pll((64).mhz(), PllClkSrc::Hsi).use_lsi().freeze();
+    // let clocks = rcc.cfgr.use_lsi().freeze().unwrap();
+    // let clocks = rcc.cfgr.use_pll((72).mhz(), PllClkSrc::Hsi).use_lsi().freeze().unwrap();
+    // let clocks = rcc.cfgr.use_pll((64).mhz(), PllClkSrc::Hsi).use_lsi().freeze().unwrap();
     let clocks = rcc.cfgr
         .use_pll((48).mhz(), PllClkSrc::HsiDiv2)
         .hclk((24).mhz())
         .use_lsi()
-        .freeze();
+        .freeze().unwrap();
 
-    // let gpioa = peripherals.GPIOA.split();
+    // let gpioa = peripherals.GPIOA.split(&mut rcc.apb2);
 
-    let gpiob = peripherals.GPIOB.split();
+    let gpiob = peripherals.GPIOB.split(&mut rcc.apb2);
     let mut led1 = gpiob.pb2.into_push_pull_output();
-    let led2 = gpiob.pb15.into_push_pull_output();
+    let led2 = gpiob.pb15.into_push_pull_output().erase();
 
     let mut delay = Delay::new(&clocks);
 
@@ -90,7 +89,7 @@ fn main() -> ! {
         LED.borrow(cs).replace(Some(led2));
     });
 
-    setup_timer1(&clocks);
+    setup_timer1(&clocks, &mut rcc.apb2);
 
     // Serial
     // let pa9 = gpioa.pa9.into_multiplex_push_pull_output();
@@ -99,7 +98,8 @@ fn main() -> ! {
     // let pb6 = gpiob.pb6.into_multiplex_push_pull_output();
     // let pb7 = gpiob.pb7.into_floating_input();
 
-    // let usart = Serial::usart1(peripherals.USART1, (pa9, pa10), (115200).bps(), &clocks);
+    // let mut afio = peripherals.AFIO.constrain(&mut rcc.apb2);
+    // let usart = Serial::usart1(peripherals.USART1, (pa9, pa10), &mut afio, &mut rcc.apb2, (115200).bps(), &clocks).unwrap();
     // let (tx, _) = usart.split();
     // let mut log = SerialWriter::new(tx);
 
@@ -116,11 +116,12 @@ fn main() -> ! {
     unreachable!();
 }
 
-fn setup_timer1(clocks: &Clocks) {
-    unsafe {
-        (*RCC::ptr()).apb2pcenr.modify(|_, w| w.tim1en().set_bit());
+fn setup_timer1(clocks: &Clocks, apb2: &mut APB2) {
+    TIM1::enable(apb2);
+    TIM1::reset(apb2);
 
-        let prescale = (clocks.hclk().0 / 1_000_000) * 100 - 1; // count for 0.1ms
+    unsafe {
+        let prescale = (clocks.pclk2_tim().0 / 1_000_000) * 100 - 1; // count for 0.1ms
         (*TIM1::ptr()).psc.write(|w| w.bits(prescale as u16));
         let down_count: u16 = 100 * 10 - 1; // 0.1ms * 10 * 100 = 100ms
         (*TIM1::ptr()).cnt.write(|w| w.bits(down_count));
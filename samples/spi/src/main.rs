@@ -10,6 +10,7 @@ use ch32v1::ch32v103; // PAC for CH32V103
 use ch32v103_hal::prelude::*;
 use ch32v103_hal::rcc::*;
 use ch32v103_hal::gpio::*;
+use ch32v103_hal::afio::*;
 use ch32v103_hal::serial::*;
 use ch32v103_hal::delay::*;
 use ch32v103_hal::spi::*;
@@ -17,18 +18,19 @@ use ch32v103_hal::spi::*;
 #[entry]
 fn main() -> ! {
     let peripherals = ch32v103::Peripherals::take().unwrap();
-    let rcc = peripherals.RCC.constrain();
+    let mut rcc = peripherals.RCC.constrain();
+    let mut afio = peripherals.AFIO.constrain(&mut rcc.apb2);
 
-    // let clocks = rcc.cfgr.freeze();
-    // let clocks = rcc.cfgr.use_pll((48).mhz(), PllClkSrc::Hsi).hclk((24).mhz()).freeze();
+    // let clocks = rcc.cfgr.freeze().unwrap();
+    // let clocks = rcc.cfgr.use_pll((48).mhz(), PllClkSrc::Hsi).hclk((24).mhz()).freeze().unwrap();
     let clocks = rcc.cfgr
         .use_pll((48).mhz(), PllClkSrc::Hsi)
         .hclk((24).mhz())
         .pclk2((6).mhz())
-        .freeze();
+        .freeze().unwrap();
 
-    let gpioa = peripherals.GPIOA.split();
-    let gpiob = peripherals.GPIOB.split();
+    let gpioa = peripherals.GPIOA.split(&mut rcc.apb2);
+    let gpiob = peripherals.GPIOB.split(&mut rcc.apb2);
 
     //SPI
     let mut pa4 = gpioa.pa4.into_push_pull_output(); // CS
@@ -37,7 +39,7 @@ fn main() -> ! {
     let pa6 = gpioa.pa6.into_floating_input(); // MISO
     let pa7 = gpioa.pa7.into_multiplex_push_pull_output(); // MOSI
 
-    let mut spi = Spi::spi1(peripherals.SPI1, (pa5, pa6, pa7), MODE_3, (100).khz(), &clocks);
+    let mut spi = Spi::spi1(peripherals.SPI1, (pa5, pa6, pa7), &mut afio, &mut rcc.apb2, MODE_3, (100).khz(), &clocks);
 
     // Serial
     let pa9 = gpioa.pa9.into_multiplex_push_pull_output();
@@ -46,7 +48,7 @@ fn main() -> ! {
     // let pb6 = gpiob.pb6.into_multiplex_push_pull_output();
     // let pb7 = gpiob.pb7.into_floating_input();
 
-    let usart = Serial::usart1(peripherals.USART1, (pa9, pa10), (115200).bps(), &clocks);
+    let usart = Serial::usart1(peripherals.USART1, (pa9, pa10), &mut afio, &mut rcc.apb2, (115200).bps(), &clocks).unwrap();
     let (tx, _) = usart.split();
     let mut log = SerialWriter::new(tx);
 
@@ -1,5 +1,5 @@
 use embedded_hal::adc::{ Channel, OneShot };
-use ch32v1::ch32v103::{ RCC, ADC };
+use ch32v1::ch32v103::ADC;
 // use crate::time::*;
 use crate::prelude::*;
 use crate::rcc::*;
@@ -7,7 +7,8 @@ use crate::gpio::*;
 use crate::delay::*;
 use crate::gpio::gpioa::{ PA0, PA1, PA2, PA3, PA4, PA5, PA6, PA7 };
 use crate::gpio::gpiob::{ PB0, PB1 };
-// use crate::gpio::gpioc::{ PC0, PC1, PC2, PC3, PC4, PC5 };
+#[cfg(feature = "r8t6")]
+use crate::gpio::gpioc::{ PC0, PC1, PC2, PC3, PC4, PC5 };
 
 macro_rules! adc_channel {
     ($PXi:ident, $i:expr) => {
@@ -31,12 +32,18 @@ adc_channel!(PA6, 6);
 adc_channel!(PA7, 7);
 adc_channel!(PB0, 8);
 adc_channel!(PB1, 9);
-// adc_channel!(PC0, 10);
-// adc_channel!(PC1, 11);
-// adc_channel!(PC2, 12);
-// adc_channel!(PC3, 13);
-// adc_channel!(PC4, 14);
-// adc_channel!(PC5, 15);
+#[cfg(feature = "r8t6")]
+adc_channel!(PC0, 10);
+#[cfg(feature = "r8t6")]
+adc_channel!(PC1, 11);
+#[cfg(feature = "r8t6")]
+adc_channel!(PC2, 12);
+#[cfg(feature = "r8t6")]
+adc_channel!(PC3, 13);
+#[cfg(feature = "r8t6")]
+adc_channel!(PC4, 14);
+#[cfg(feature = "r8t6")]
+adc_channel!(PC5, 15);
 
 pub struct Adc<ADC> {
     adc: ADC,
@@ -44,20 +51,20 @@ pub struct Adc<ADC> {
     tconv: u32,
 }
 
+// Conversion time in us. ADCPRE is set by CFGR.
+fn conversion_time(clocks: &Clocks) -> u32 {
+    // 239.5 + 12.5 cycles
+    252 * 1_000_000 / clocks.adcclk().0 + 1
+}
+
 impl<ADCX> Adc<ADCX> {
-    pub fn adc(adc: ADCX, clocks: &Clocks) -> Self {
-        let (adcpre_bits, adc_clock) = match clocks.pclk2().0 / 14_000_000 {
-            0..=1 => (0b00, clocks.pclk2().0 / 2),
-            2..=3 => (0b01, clocks.pclk2().0 / 4),
-            4..=5 => (0b10, clocks.pclk2().0 / 6),
-            _ => (0x11, clocks.pclk2().0 / 8),
-        };
+    pub fn adc(adc: ADCX, apb2: &mut APB2, clocks: &Clocks) -> Self {
+        // enable ADC
+        ADC::enable(apb2);
+        ADC::reset(apb2);
+        let tconv = conversion_time(clocks);
 
         unsafe {
-            // enable ADC
-            (*RCC::ptr()).apb2pcenr.modify(|_, w| w.adcen().set_bit());
-            // set ADC clock's prescale
-            (*RCC::ptr()).cfgr0.modify(|_, w| w.adcpre().bits(adcpre_bits));
             // set every SMP 0b111 or 239.5 cycles
             // slow is not worse than too fast.
             // TODO: make interface to change SMPx
@@ -65,14 +72,19 @@ impl<ADCX> Adc<ADCX> {
             (*ADC::ptr()).samptr2.write(|w| w.bits(0xffff_ffff));
         }
 
-        let tconv = (240.0 + 12.5) / ((adc_clock / 1_000_000) as f32);
         Self {
             adc: adc,
             delay: Delay::new(clocks),
-            tconv: (tconv as u32) + 1, // in us
+            tconv: tconv,
         }
     }
 
+    // Recompute conversion time after CFGR::reconfigure().
+    pub fn reconfigure(&mut self, clocks: &Clocks) {
+        self.tconv = conversion_time(clocks);
+        self.delay.reconfigure(clocks);
+    }
+
     fn power_up(&mut self) {
         unsafe {
             (*ADC::ptr()).ctlr2.modify(|_, w| w.adon().set_bit());
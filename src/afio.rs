@@ -0,0 +1,144 @@
+use ch32v1::ch32v103::{ AFIO, USART1, USART2, USART3, SPI1, I2C1 };
+use crate::rcc::{ Enable, APB2 };
+use crate::gpio::*;
+use crate::gpio::gpioa::{ PA13, PA14, PA15 };
+use crate::gpio::gpiob::{ PB3, PB4 };
+
+pub trait AfioExt {
+    type Afio;
+    fn constrain(self, apb2: &mut APB2) -> Self::Afio;
+}
+
+impl AfioExt for AFIO {
+    type Afio = Afio;
+
+    fn constrain(self, apb2: &mut APB2) -> Afio {
+        // clock is required to write AFIO registers.
+        AFIO::enable(apb2);
+
+        Afio { _afio: self, swcfg: 0b000 }
+    }
+}
+
+// Pin sets select remap at compile time.
+// Pins<PERIPH, REMAP> is implemented only for valid pin tuples of PERIPH.
+pub trait Pins<PERIPH, REMAP> {}
+
+// Remap modes
+pub struct NoRemap;
+pub struct PartialRemap;
+pub struct Remap;
+
+// Value of remap field. Cut by the field width.
+pub trait RemapMode {
+    const VALUE: u32;
+}
+
+impl RemapMode for NoRemap {
+    const VALUE: u32 = 0b00;
+}
+
+impl RemapMode for PartialRemap {
+    const VALUE: u32 = 0b01;
+}
+
+impl RemapMode for Remap {
+    const VALUE: u32 = 0b11;
+}
+
+// Remap field of the peripheral in PCFR
+pub trait RemapField {
+    const MASK: u32;
+}
+
+impl RemapField for SPI1 {
+    const MASK: u32 = 0b1 << 0;
+}
+
+impl RemapField for I2C1 {
+    const MASK: u32 = 0b1 << 1;
+}
+
+impl RemapField for USART1 {
+    const MASK: u32 = 0b1 << 2;
+}
+
+impl RemapField for USART2 {
+    const MASK: u32 = 0b1 << 3;
+}
+
+impl RemapField for USART3 {
+    const MASK: u32 = 0b11 << 4;
+}
+
+// Owner of AFIO registers
+pub struct Afio {
+    _afio: AFIO,
+    // SWCFG is write only. Keep the last written value.
+    swcfg: u32,
+}
+
+impl Afio {
+    // Write PCFR bits in mask without changing SWCFG.
+    pub(crate) fn modify_pcfr(&mut self, mask: u32, bits: u32) {
+        let swcfg = self.swcfg;
+        unsafe {
+            (*AFIO::ptr()).pcfr.modify(|r, w|
+                w.bits((r.bits() & !mask & !(0b111 << 24)) | (bits & mask) | (swcfg << 24))
+            );
+        }
+    }
+
+    // Write remap field of PERIPH.
+    pub(crate) fn remap<PERIPH: RemapField, REMAP: RemapMode>(&mut self) {
+        let bits = REMAP::VALUE << PERIPH::MASK.trailing_zeros();
+        self.modify_pcfr(PERIPH::MASK, bits);
+    }
+
+    fn set_swcfg(&mut self, swcfg: u32) {
+        self.swcfg = swcfg;
+        self.modify_pcfr(0, 0);
+    }
+
+    // Keep SWD and release JTAG pins PA15, PB3 and PB4.
+    pub fn disable_jtag(
+        &mut self,
+        pa15: PA15<Debugger>,
+        pb3: PB3<Debugger>,
+        pb4: PB4<Debugger>,
+    ) -> (
+        PA15<Input<Floating>>,
+        PB3<Input<Floating>>,
+        PB4<Input<Floating>>,
+    ) {
+        self.set_swcfg(0b010);
+
+        (pa15.release(), pb3.release(), pb4.release())
+    }
+
+    // Release all debug pins. WCH-Link cannot connect until reset.
+    pub fn disable_swd(
+        &mut self,
+        pa13: PA13<Debugger>,
+        pa14: PA14<Debugger>,
+        pa15: PA15<Debugger>,
+        pb3: PB3<Debugger>,
+        pb4: PB4<Debugger>,
+    ) -> (
+        PA13<Input<Floating>>,
+        PA14<Input<Floating>>,
+        PA15<Input<Floating>>,
+        PB3<Input<Floating>>,
+        PB4<Input<Floating>>,
+    ) {
+        self.set_swcfg(0b100);
+
+        (
+            pa13.release(),
+            pa14.release(),
+            pa15.release(),
+            pb3.release(),
+            pb4.release(),
+        )
+    }
+}
\ No newline at end of file
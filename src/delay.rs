@@ -38,6 +38,21 @@ impl Delay {
         }
     }
 
+    // Follow HCLK changed by CFGR::reconfigure().
+    pub fn reconfigure(&mut self, clocks: &Clocks) {
+        self.hclk = clocks.hclk().0;
+    }
+
+    // Count HCLK/8 cycles while f() is running. Used to measure clocks.
+    pub(crate) fn count_cycles<F>(&mut self, f: F) -> u32 where F: FnOnce() {
+        self.stop_count();
+        self.set_counter(0);
+        self.start_count();
+        f();
+        self.stop_count();
+        unsafe { (self.cntl as *mut u32).read_volatile() }
+    }
+
     fn has_wrapped(&self) -> bool {
         unsafe {
             let valh = (self.cnth as *mut usize).read_volatile();
@@ -102,6 +117,8 @@ impl Delay {
     fn do_standby(&self) {
         unsafe {
             (*PWR::ptr()).ctlr.modify(|_, w| w.pdds().set_bit());
+            // Clear the old wakeup flag (CWUF) not to wake up immediately.
+            (*PWR::ptr()).ctlr.modify(|r, w| w.bits(r.bits() | (0b1 << 2)));
             (*PFIC::ptr()).sctlr.modify(|_, w| w.sleepdeep().set_bit());
             // (*PFIC::ptr()).sctlr.modify(|_, w| w.sleepdeep().clear_bit().wfitowfe().set_bit());
             asm!("wfi");
@@ -186,6 +203,7 @@ impl Delay {
     pub fn standby_ms(&mut self, duration: u32) {
         // stop specified ms. Max. 2^32ms = about 49 days.
         // if 0, never auto wake-up.
+        // After restart, rcc::ResetReason::read_and_clear().is_standby_wakeup() returns true.
 
         if duration == 0 {
             self.do_standby();
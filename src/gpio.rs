@@ -1,10 +1,18 @@
 use core::marker::PhantomData;
+use core::convert::Infallible;
+use embedded_hal::digital::v2::{ OutputPin, InputPin, StatefulOutputPin, ToggleableOutputPin };
+use ch32v1::ch32v103::{ GPIOA, GPIOB, GPIOC, GPIOD, AFIO, EXTI, PFIC };
+use ch32v1::ch32v103::Interrupt;
+use crate::afio::Afio;
+use crate::rcc::APB2;
+
+pub use embedded_hal::digital::v2::PinState;
 
 // Extend PAC::GPIOX to get individual pins
 pub trait GpioExt {
     type Parts;
 
-    fn split(self) -> Self::Parts;
+    fn split(self, apb2: &mut APB2) -> Self::Parts;
 }
 
 // Input Mode
@@ -29,32 +37,406 @@ pub struct AltOutput<MODE> {
 pub struct PushPull;
 pub struct OpenDrain;
 
+// Mode changeable at runtime by make_*() methods.
+// The current mode is read from CFGR.
+pub struct Dynamic;
+
+// Pin owned by SWD or JTAG after reset. No into_*() until released by
+// Afio::disable_jtag() or Afio::disable_swd().
+pub struct Debugger;
+
+// Modes which can be changed by into_*(). Not for Debugger.
+pub trait Configurable {}
+
+impl<MODE> Configurable for Input<MODE> {}
+impl<MODE> Configurable for Output<MODE> {}
+impl<MODE> Configurable for AltOutput<MODE> {}
+impl Configurable for Dynamic {}
+
+// Returned if the operation is not allowed in the current Dynamic mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinModeError {
+    IncorrectMode,
+}
+
+// Maximum speed of Output and AltOutput. MODE bits in CFGR.
+// into_*_output() set Mhz50. Slower is better for EMI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed {
+    Mhz10 = 0b01,
+    Mhz2 = 0b10,
+    Mhz50 = 0b11,
+}
+
+// Access registers of the port selected at runtime.
+macro_rules! with_port {
+    ($port:expr, |$gpio:ident| $body:expr) => {
+        unsafe {
+            match $port {
+                0 => { let $gpio = &*GPIOA::ptr(); $body }
+                1 => { let $gpio = &*GPIOB::ptr(); $body }
+                2 => { let $gpio = &*GPIOC::ptr(); $body }
+                _ => { let $gpio = &*GPIOD::ptr(); $body }
+            }
+        }
+    };
+}
+
+// Port and pin number erased pin
+// Made by erase() and can be put into an array with other pins.
+pub struct Pin<MODE> {
+    port: u8,
+    i: u8,
+    _mode: PhantomData<MODE>,
+}
+
+impl<MODE> InputPin for Pin<Input<MODE>> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(with_port!(self.port, |gpio| gpio.indr.read().bits() & (0b1 << self.i) > 0))
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(with_port!(self.port, |gpio| gpio.indr.read().bits() & (0b1 << self.i) == 0))
+    }
+}
+
+impl<MODE> OutputPin for Pin<Output<MODE>> {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        with_port!(self.port, |gpio| gpio.bshr.write(|w| w.bits(0b1 << self.i)));
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        with_port!(self.port, |gpio| gpio.bcr.write(|w| w.bits(0b1 << self.i)));
+        Ok(())
+    }
+}
+
+impl<MODE> StatefulOutputPin for Pin<Output<MODE>> {
+    // Return last set value, not acutual state.
+    fn is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(with_port!(self.port, |gpio| gpio.outdr.read().bits() & (0b1 << self.i) > 0))
+    }
+
+    fn is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(with_port!(self.port, |gpio| gpio.outdr.read().bits() & (0b1 << self.i) == 0))
+    }
+}
+
+impl<MODE> ToggleableOutputPin for Pin<Output<MODE>> {
+    type Error = Infallible;
+
+    // Read OUTDR and write BSHR in a critical section, so an interrupt can not change the pin in between.
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        riscv::interrupt::free(|| {
+            with_port!(self.port, |gpio| {
+                let outdr = gpio.outdr.read().bits();
+                let mask = 0b1 << self.i;
+                gpio.bshr.write(|w| w.bits(((outdr & mask) << 16) | (!outdr & mask)));
+            });
+        });
+        Ok(())
+    }
+}
+
+impl InputPin for Pin<Output<OpenDrain>> {
+    type Error = Infallible;
+
+    // Read actual level of the line.
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(with_port!(self.port, |gpio| gpio.indr.read().bits() & (0b1 << self.i) > 0))
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(with_port!(self.port, |gpio| gpio.indr.read().bits() & (0b1 << self.i) == 0))
+    }
+}
+
+// Pin whose configuration is locked by LCKR until next reset.
+// into_*() are not available, but the pin is still usable.
+pub struct Locked<PIN> {
+    pin: PIN,
+}
+
+// LCKR is frozen once LCKK is set, so only one lock sequence works per port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockError {
+    // LCKR of the port was already locked.
+    PortLocked,
+    // LCKK or the lock bit of the pin was not set by the key sequence.
+    NotLocked,
+}
+
+// Lock pins in mask of the port by one key sequence.
+fn lock_port(port: u8, mask: u32) -> Result<(), LockError> {
+    with_port!(port, |gpio| {
+        if gpio.lckr.read().bits() & (0b1 << 16) != 0 {
+            return Err(LockError::PortLocked);
+        }
+        // Key sequence: LCKK 1, 0, 1 with the same lock bits, then read twice.
+        riscv::interrupt::free(|| {
+            gpio.lckr.write(|w| w.bits((0b1 << 16) | mask));
+            gpio.lckr.write(|w| w.bits(mask));
+            gpio.lckr.write(|w| w.bits((0b1 << 16) | mask));
+            gpio.lckr.read();
+            gpio.lckr.read();
+        });
+        if gpio.lckr.read().bits() & ((0b1 << 16) | mask) != (0b1 << 16) | mask {
+            return Err(LockError::NotLocked);
+        }
+        Ok(())
+    })
+}
+
+// Pin of the port P
+pub trait PortPin<P> {
+    const PORT: u8;
+    const MASK: u32;
+}
+
+// Tuple of pins of the port P for lock_pins()
+pub trait LockPins<P>: Sized {
+    type Locked;
+    const PORT: u8;
+    const MASK: u32;
+
+    fn into_locked(self) -> Self::Locked;
+}
+
+macro_rules! lock_pins {
+    ($A:ident, $($B:ident),+) => {
+        impl<P, $A: PortPin<P>, $($B: PortPin<P>),+> LockPins<P> for ($A, $($B),+) {
+            type Locked = (Locked<$A>, $(Locked<$B>),+);
+            const PORT: u8 = $A::PORT;
+            const MASK: u32 = $A::MASK $(| $B::MASK)+;
+
+            #[allow(non_snake_case)]
+            fn into_locked(self) -> Self::Locked {
+                let ($A, $($B),+) = self;
+                (Locked { pin: $A }, $(Locked { pin: $B }),+)
+            }
+        }
+    };
+}
+
+lock_pins!(A, B);
+lock_pins!(A, B, C);
+lock_pins!(A, B, C, D);
+lock_pins!(A, B, C, D, E);
+lock_pins!(A, B, C, D, E, F);
+
+// Lock several pins of one port by one key sequence; e.g. lock_pins((pa1, pa4)).
+// LCKR of the port is frozen after that, so lock all pins of a port together.
+// The pins are returned with the error.
+pub fn lock_pins<P, PINS: LockPins<P>>(pins: PINS) -> Result<PINS::Locked, (PINS, LockError)> {
+    match lock_port(PINS::PORT, PINS::MASK) {
+        Ok(()) => Ok(pins.into_locked()),
+        Err(error) => Err((pins, error)),
+    }
+}
+
+impl<PIN: InputPin> InputPin for Locked<PIN> {
+    type Error = PIN::Error;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        self.pin.is_high()
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.pin.is_low()
+    }
+}
+
+impl<PIN: OutputPin> OutputPin for Locked<PIN> {
+    type Error = PIN::Error;
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.pin.set_high()
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.pin.set_low()
+    }
+}
+
+impl<PIN: StatefulOutputPin> StatefulOutputPin for Locked<PIN> {
+    fn is_set_high(&self) -> Result<bool, Self::Error> {
+        self.pin.is_set_high()
+    }
+
+    fn is_set_low(&self) -> Result<bool, Self::Error> {
+        self.pin.is_set_low()
+    }
+}
+
+impl<PIN: ToggleableOutputPin> ToggleableOutputPin for Locked<PIN> {
+    type Error = PIN::Error;
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        self.pin.toggle()
+    }
+}
+
+// Edge to trigger EXTI
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+// External interrupt on input pins
+// EXTI line N is shared by pin N of all ports. Only one port can be selected for a line.
+pub trait ExtiPin {
+    // Select this pin for the EXTI line.
+    fn make_interrupt_source(&mut self, afio: &mut Afio);
+    fn trigger_on_edge(&mut self, exti: &mut EXTI, edge: Edge);
+    // Unmask EXTI line and enable the interrupt in PFIC.
+    fn enable_interrupt(&mut self, exti: &mut EXTI);
+    // Mask EXTI line only. The vector may be shared by other lines.
+    fn disable_interrupt(&mut self, exti: &mut EXTI);
+    fn clear_interrupt_pending_bit(&mut self);
+    fn check_interrupt(&self) -> bool;
+    // EXTI0-EXTI4, EXTI9_5 or EXTI15_10
+    fn interrupt(&self) -> Interrupt;
+}
+
+// Afio is borrowed to own EXTICR.
+fn exti_make_source(_afio: &mut Afio, port: u8, i: u8) {
+    unsafe {
+        let afio = &*AFIO::ptr();
+        let offset = 4 * (i & 0b11);
+        let port = port as u32;
+        match i >> 2 {
+            0 => afio.exticr1.modify(|r, w| w.bits(r.bits() & !(0b1111 << offset) | (port << offset))),
+            1 => afio.exticr2.modify(|r, w| w.bits(r.bits() & !(0b1111 << offset) | (port << offset))),
+            2 => afio.exticr3.modify(|r, w| w.bits(r.bits() & !(0b1111 << offset) | (port << offset))),
+            _ => afio.exticr4.modify(|r, w| w.bits(r.bits() & !(0b1111 << offset) | (port << offset))),
+        }
+    }
+}
+
+fn exti_trigger_on_edge(exti: &mut EXTI, i: u8, edge: Edge) {
+    let (rising, falling) = match edge {
+        Edge::Rising => (true, false),
+        Edge::Falling => (false, true),
+        Edge::Both => (true, true),
+    };
+    unsafe {
+        exti.rtenr.modify(|r, w| w.bits(r.bits() & !(0b1 << i) | ((rising as u32) << i)));
+        exti.ftenr.modify(|r, w| w.bits(r.bits() & !(0b1 << i) | ((falling as u32) << i)));
+    }
+}
+
+fn exti_interrupt(i: u8) -> Interrupt {
+    match i {
+        0 => Interrupt::EXTI0,
+        1 => Interrupt::EXTI1,
+        2 => Interrupt::EXTI2,
+        3 => Interrupt::EXTI3,
+        4 => Interrupt::EXTI4,
+        5..=9 => Interrupt::EXTI9_5,
+        _ => Interrupt::EXTI15_10,
+    }
+}
+
+fn exti_enable_interrupt(exti: &mut EXTI, i: u8) {
+    unsafe {
+        exti.intenr.modify(|r, w| w.bits(r.bits() | (0b1 << i)));
+
+        let irq = exti_interrupt(i) as u32;
+        if irq < 32 {
+            (*PFIC::ptr()).ienr1.modify(|_, w| w.bits(0b1 << irq));
+        } else {
+            (*PFIC::ptr()).ienr2.modify(|_, w| w.bits(0b1 << (irq - 32)));
+        }
+    }
+}
+
+fn exti_disable_interrupt(exti: &mut EXTI, i: u8) {
+    unsafe {
+        exti.intenr.modify(|r, w| w.bits(r.bits() & !(0b1 << i)));
+    }
+}
+
+fn exti_clear_pending(i: u8) {
+    unsafe {
+        // Write 1 to clear
+        (*EXTI::ptr()).intfr.write(|w| w.bits(0b1 << i));
+    }
+}
+
+fn exti_check(i: u8) -> bool {
+    unsafe { (*EXTI::ptr()).intfr.read().bits() & (0b1 << i) > 0 }
+}
+
+impl<MODE> ExtiPin for Pin<Input<MODE>> {
+    fn make_interrupt_source(&mut self, afio: &mut Afio) {
+        exti_make_source(afio, self.port, self.i);
+    }
+
+    fn trigger_on_edge(&mut self, exti: &mut EXTI, edge: Edge) {
+        exti_trigger_on_edge(exti, self.i, edge);
+    }
+
+    fn enable_interrupt(&mut self, exti: &mut EXTI) {
+        exti_enable_interrupt(exti, self.i);
+    }
+
+    fn disable_interrupt(&mut self, exti: &mut EXTI) {
+        exti_disable_interrupt(exti, self.i);
+    }
+
+    fn clear_interrupt_pending_bit(&mut self) {
+        exti_clear_pending(self.i);
+    }
+
+    fn check_interrupt(&self) -> bool {
+        exti_check(self.i)
+    }
+
+    fn interrupt(&self) -> Interrupt {
+        exti_interrupt(self.i)
+    }
+}
+
 // define Ports
 macro_rules! gpio {
     (
         $GPIOX:ident,
         $gpiox:ident,
         $x:expr,
-        [$($PXi:ident: ($pxi:ident, $i:expr, $MODE:ty, $CFGR:ident),)+]
+        $PXx:ident,
+        [$($(#[$attr:meta])* $PXi:ident: ($pxi:ident, $i:expr, $MODE:ty, $CFGR:ident),)+]
     ) => {
         // define GPIOX
         pub mod $gpiox {
             use core::marker::PhantomData;
             use core::convert::Infallible;
             use embedded_hal::digital::v2::{ OutputPin, InputPin, StatefulOutputPin, ToggleableOutputPin };
-            use ch32v1::ch32v103::RCC;
-            use ch32v1::ch32v103::$GPIOX;
+            use ch32v1::ch32v103::{ $GPIOX, EXTI };
+            use crate::afio::Afio;
+            use ch32v1::ch32v103::Interrupt;
+            use crate::rcc::{ Enable, Reset, APB2 };
 
             // Use struct defined in outer scope
             use super::{
-                GpioExt,
+                GpioExt, Pin, Speed, ExtiPin, Edge, PinState, Locked, LockError, PortPin, lock_port,
+                exti_make_source, exti_trigger_on_edge, exti_enable_interrupt, exti_disable_interrupt,
+                exti_clear_pending, exti_check, exti_interrupt,
                 Input, Analog, Floating, PullDown, PullUp,
                 Output, AltOutput, PushPull, OpenDrain,
+                Dynamic, Debugger, Configurable, PinModeError,
             };
 
             pub struct Parts {
                 $(
                     // define pins with default state
+                    $(#[$attr])*
                     pub $pxi: $PXi<$MODE>,
                 )+
             }
@@ -63,27 +445,248 @@ macro_rules! gpio {
             impl GpioExt for $GPIOX {
                 type Parts = Parts;
 
-                fn split(self) -> Parts {
-                    unsafe {
-                        // (*RCC::ptr()).apb2pcenr.modify(|_, w| w.iopaen().set_bit());
-                        (*RCC::ptr()).apb2pcenr.modify(|r, w| w.bits((r.bits() | (0b1 << ($x + 2)))));
-                    }
+                fn split(self, apb2: &mut APB2) -> Parts {
+                    $GPIOX::enable(apb2);
+                    $GPIOX::reset(apb2);
 
                     Parts {
                         $(
+                            $(#[$attr])*
                             $pxi: $PXi { _mode: PhantomData },
                         )+
                     }
                 }
             }
 
+            // Pin number erased pin
+            // Made by erase_number() and can be put into an array with other pins of this port.
+            pub struct $PXx<MODE> {
+                i: u8,
+                _mode: PhantomData<MODE>,
+            }
+
+            impl<MODE> $PXx<MODE> {
+                // Erase port too.
+                pub fn erase(self) -> Pin<MODE> {
+                    Pin { port: $x, i: self.i, _mode: PhantomData }
+                }
+            }
+
+            impl<MODE> InputPin for $PXx<Input<MODE>> {
+                type Error = Infallible;
+
+                fn is_high(&self) -> Result<bool, Self::Error> {
+                    unsafe {
+                        Ok((*$GPIOX::ptr()).indr.read().bits() & (0b1 << self.i) > 0)
+                    }
+                }
+
+                fn is_low(&self) -> Result<bool, Self::Error> {
+                    unsafe {
+                        Ok((*$GPIOX::ptr()).indr.read().bits() & (0b1 << self.i) == 0)
+                    }
+                }
+            }
+
+            impl<MODE> OutputPin for $PXx<Output<MODE>> {
+                type Error = Infallible;
+
+                fn set_high(&mut self) -> Result<(), Self::Error> {
+                    unsafe {
+                        (*$GPIOX::ptr()).bshr.write(|w| w.bits(0b1 << self.i));
+                    }
+                    Ok(())
+                }
+
+                fn set_low(&mut self) -> Result<(), Self::Error> {
+                    unsafe {
+                        (*$GPIOX::ptr()).bcr.write(|w| w.bits(0b1 << self.i));
+                    }
+                    Ok(())
+                }
+            }
+
+            impl<MODE> StatefulOutputPin for $PXx<Output<MODE>> {
+                // Return last set value, not acutual state.
+                fn is_set_high(&self) -> Result<bool, Self::Error> {
+                    unsafe {
+                        Ok((*$GPIOX::ptr()).outdr.read().bits() & (0b1 << self.i) > 0)
+                    }
+                }
+
+                fn is_set_low(&self) -> Result<bool, Self::Error> {
+                    unsafe {
+                        Ok((*$GPIOX::ptr()).outdr.read().bits() & (0b1 << self.i) == 0)
+                    }
+                }
+            }
+
+            impl<MODE> ToggleableOutputPin for $PXx<Output<MODE>> {
+                type Error = Infallible;
+
+                // Read OUTDR and write BSHR in a critical section, so an interrupt can not change the pin in between.
+                fn toggle(&mut self) -> Result<(), Self::Error> {
+                    riscv::interrupt::free(|| unsafe {
+                        let outdr = (*$GPIOX::ptr()).outdr.read().bits();
+                        let mask = 0b1 << self.i;
+                        (*$GPIOX::ptr()).bshr.write(|w| w.bits(((outdr & mask) << 16) | (!outdr & mask)));
+                    });
+                    Ok(())
+                }
+            }
+
+            impl InputPin for $PXx<Output<OpenDrain>> {
+                type Error = Infallible;
+
+                // Read actual level of the line.
+                fn is_high(&self) -> Result<bool, Self::Error> {
+                    unsafe {
+                        Ok((*$GPIOX::ptr()).indr.read().bits() & (0b1 << self.i) > 0)
+                    }
+                }
+
+                fn is_low(&self) -> Result<bool, Self::Error> {
+                    unsafe {
+                        Ok((*$GPIOX::ptr()).indr.read().bits() & (0b1 << self.i) == 0)
+                    }
+                }
+            }
+
+            impl<MODE> ExtiPin for $PXx<Input<MODE>> {
+                fn make_interrupt_source(&mut self, afio: &mut Afio) {
+                    exti_make_source(afio, $x, self.i);
+                }
+
+                fn trigger_on_edge(&mut self, exti: &mut EXTI, edge: Edge) {
+                    exti_trigger_on_edge(exti, self.i, edge);
+                }
+
+                fn enable_interrupt(&mut self, exti: &mut EXTI) {
+                    exti_enable_interrupt(exti, self.i);
+                }
+
+                fn disable_interrupt(&mut self, exti: &mut EXTI) {
+                    exti_disable_interrupt(exti, self.i);
+                }
+
+                fn clear_interrupt_pending_bit(&mut self) {
+                    exti_clear_pending(self.i);
+                }
+
+                fn check_interrupt(&self) -> bool {
+                    exti_check(self.i)
+                }
+
+                fn interrupt(&self) -> Interrupt {
+                    exti_interrupt(self.i)
+                }
+            }
+
+            // Output pins on this port written by one BSHR access.
+            // Bit k of the value goes to pins[k].
+            pub struct PortWriter<MODE, const N: usize> {
+                pins: [$PXx<Output<MODE>>; N],
+            }
+
+            impl<MODE, const N: usize> PortWriter<MODE, N> {
+                pub fn new(pins: [$PXx<Output<MODE>>; N]) -> Self {
+                    PortWriter { pins }
+                }
+
+                pub fn write(&mut self, value: u32) {
+                    let mut set: u32 = 0;
+                    let mut reset: u32 = 0;
+                    for (k, pin) in self.pins.iter().enumerate() {
+                        if value & (0b1 << k) > 0 {
+                            set |= 0b1 << pin.i;
+                        } else {
+                            reset |= 0b1 << pin.i;
+                        }
+                    }
+                    unsafe {
+                        (*$GPIOX::ptr()).bshr.write(|w| w.bits((reset << 16) | set));
+                    }
+                }
+
+                pub fn release(self) -> [$PXx<Output<MODE>>; N] {
+                    self.pins
+                }
+            }
+
+            // Input pins on this port read by one INDR access.
+            // Bit k of the value comes from pins[k].
+            pub struct PortReader<MODE, const N: usize> {
+                pins: [$PXx<Input<MODE>>; N],
+            }
+
+            impl<MODE, const N: usize> PortReader<MODE, N> {
+                pub fn new(pins: [$PXx<Input<MODE>>; N]) -> Self {
+                    PortReader { pins }
+                }
+
+                pub fn read(&self) -> u32 {
+                    let indr = unsafe { (*$GPIOX::ptr()).indr.read().bits() };
+                    let mut value: u32 = 0;
+                    for (k, pin) in self.pins.iter().enumerate() {
+                        value |= ((indr >> pin.i) & 0b1) << k;
+                    }
+                    value
+                }
+
+                pub fn release(self) -> [$PXx<Input<MODE>>; N] {
+                    self.pins
+                }
+            }
+
             $(
+                $(#[$attr])*
                 pub struct $PXi<MODE> {
                     _mode: PhantomData<MODE>,
                 }
 
+                $(#[$attr])*
+                impl<MODE: Configurable> PortPin<$GPIOX> for $PXi<MODE> {
+                    const PORT: u8 = $x;
+                    const MASK: u32 = 0b1 << $i;
+                }
+
+                // Only debug pins are made in Debugger mode.
+                $(#[$attr])*
+                #[allow(dead_code)]
+                impl $PXi<Debugger> {
+                    // Called by Afio after SWCFG released the pin.
+                    pub(crate) fn release(self) -> $PXi<Input<Floating>> {
+                        $PXi::<Input<Floating>> { _mode: PhantomData }.into_floating_input()
+                    }
+                }
+
                 // Impliment fn to set pins mode
-                impl<MODE> $PXi<MODE> {
+                $(#[$attr])*
+                impl<MODE: Configurable> $PXi<MODE> {
+                    // Erase pin number, keep port.
+                    pub fn erase_number(self) -> $PXx<MODE> {
+                        $PXx { i: $i, _mode: PhantomData }
+                    }
+
+                    // Erase port and pin number.
+                    pub fn erase(self) -> Pin<MODE> {
+                        Pin { port: $x, i: $i, _mode: PhantomData }
+                    }
+
+                    // Lock CFGR of this pin until next reset.
+                    // LCKR of the port is frozen after that; use lock_pins() to lock several pins of a port.
+                    // The pin is returned with the error.
+                    pub fn lock(self) -> Result<Locked<Self>, (Self, LockError)> {
+                        match lock_port($x, 0b1 << $i) {
+                            Ok(()) => Ok(Locked { pin: self }),
+                            Err(error) => Err((self, error)),
+                        }
+                    }
+
+                    pub fn into_dynamic(self) -> $PXi<Dynamic> {
+                        $PXi { _mode: PhantomData }
+                    }
+
                     pub fn into_analog_input(self) -> $PXi<Input<Analog>> {
                         unsafe {
                             let offset = 4 * ($i & 0b111);
@@ -186,6 +789,30 @@ macro_rules! gpio {
                         $PXi { _mode: PhantomData }
                     }
 
+                    // Set OUTDR before changing mode to avoid a glitch.
+                    pub fn into_open_drain_output_with_state(self, state: PinState) -> $PXi<Output<OpenDrain>> {
+                        unsafe {
+                            match state {
+                                PinState::High => (*$GPIOX::ptr()).bshr.write(|w| w.bits(0b1 << $i)),
+                                PinState::Low => (*$GPIOX::ptr()).bcr.write(|w| w.bits(0b1 << $i)),
+                            }
+                        }
+
+                        self.into_open_drain_output()
+                    }
+
+                    // Set OUTDR before changing mode to avoid a glitch.
+                    pub fn into_push_pull_output_with_state(self, state: PinState) -> $PXi<Output<PushPull>> {
+                        unsafe {
+                            match state {
+                                PinState::High => (*$GPIOX::ptr()).bshr.write(|w| w.bits(0b1 << $i)),
+                                PinState::Low => (*$GPIOX::ptr()).bcr.write(|w| w.bits(0b1 << $i)),
+                            }
+                        }
+
+                        self.into_push_pull_output()
+                    }
+
                     pub fn into_multiplex_push_pull_output(self) -> $PXi<AltOutput<PushPull>> {
                         unsafe {
                             let offset = 4 * ($i & 0b111);
@@ -219,6 +846,168 @@ macro_rules! gpio {
                     }
                 }
 
+                $(#[$attr])*
+                impl $PXi<Dynamic> {
+                    fn set_cfgr(&mut self, mode: u32, cnf: u32) {
+                        unsafe {
+                            let offset = 4 * ($i & 0b111);
+                            // Reset target bits, and set the target mode and cnf bits.
+                            (*$GPIOX::ptr()).$CFGR.modify(|r, w| w.bits((r.bits() & !(0b1111 << offset) | (mode << offset) | (cnf << (offset + 2)))));
+                        }
+                    }
+
+                    // MODE and CNF bits
+                    fn cfgr(&self) -> (u32, u32) {
+                        unsafe {
+                            let offset = 4 * ($i & 0b111);
+                            let bits = (*$GPIOX::ptr()).$CFGR.read().bits() >> offset;
+                            (bits & 0b11, (bits >> 2) & 0b11)
+                        }
+                    }
+
+                    pub fn make_floating_input(&mut self) {
+                        self.set_cfgr(0b00, 0b01);
+                    }
+
+                    pub fn make_pull_up_input(&mut self) {
+                        // Switch to input first, so that an output pin isn't driven by the new OUTDR.
+                        self.set_cfgr(0b00, 0b10);
+                        unsafe {
+                            // Set OUTDR for pull-up.
+                            (*$GPIOX::ptr()).bshr.write(|w| w.bits(0b1 << $i));
+                        }
+                    }
+
+                    pub fn make_pull_down_input(&mut self) {
+                        // Switch to input first, so that an output pin isn't driven by the new OUTDR.
+                        self.set_cfgr(0b00, 0b10);
+                        unsafe {
+                            // Clear OUTDR for pull-down.
+                            (*$GPIOX::ptr()).bcr.write(|w| w.bits(0b1 << $i));
+                        }
+                    }
+
+                    pub fn make_push_pull_output(&mut self) {
+                        self.set_cfgr(0b11, 0b00);
+                    }
+
+                    pub fn make_open_drain_output(&mut self) {
+                        self.set_cfgr(0b11, 0b01);
+                    }
+
+                    // Readable in input modes and open-drain output.
+                    fn is_readable(&self) -> bool {
+                        match self.cfgr() {
+                            // Analog input
+                            (0b00, 0b00) => false,
+                            (0b00, _) => true,
+                            (_, cnf) => cnf == 0b01,
+                        }
+                    }
+
+                    // Writable in general output modes.
+                    fn is_writable(&self) -> bool {
+                        let (mode, cnf) = self.cfgr();
+                        mode != 0b00 && cnf & 0b10 == 0
+                    }
+                }
+
+                $(#[$attr])*
+                impl InputPin for $PXi<Dynamic> {
+                    type Error = PinModeError;
+
+                    fn is_high(&self) -> Result<bool, Self::Error> {
+                        if !self.is_readable() {
+                            return Err(PinModeError::IncorrectMode);
+                        }
+                        unsafe {
+                            Ok((*$GPIOX::ptr()).indr.read().bits() & (0b1 << $i) > 0)
+                        }
+                    }
+
+                    fn is_low(&self) -> Result<bool, Self::Error> {
+                        self.is_high().map(|high| !high)
+                    }
+                }
+
+                $(#[$attr])*
+                impl OutputPin for $PXi<Dynamic> {
+                    type Error = PinModeError;
+
+                    fn set_high(&mut self) -> Result<(), Self::Error> {
+                        if !self.is_writable() {
+                            return Err(PinModeError::IncorrectMode);
+                        }
+                        unsafe {
+                            (*$GPIOX::ptr()).bshr.write(|w| w.bits(0b1 << $i));
+                        }
+                        Ok(())
+                    }
+
+                    fn set_low(&mut self) -> Result<(), Self::Error> {
+                        if !self.is_writable() {
+                            return Err(PinModeError::IncorrectMode);
+                        }
+                        unsafe {
+                            (*$GPIOX::ptr()).bcr.write(|w| w.bits(0b1 << $i));
+                        }
+                        Ok(())
+                    }
+                }
+
+                $(#[$attr])*
+                impl<MODE> ExtiPin for $PXi<Input<MODE>> {
+                    fn make_interrupt_source(&mut self, afio: &mut Afio) {
+                        exti_make_source(afio, $x, $i);
+                    }
+
+                    fn trigger_on_edge(&mut self, exti: &mut EXTI, edge: Edge) {
+                        exti_trigger_on_edge(exti, $i, edge);
+                    }
+
+                    fn enable_interrupt(&mut self, exti: &mut EXTI) {
+                        exti_enable_interrupt(exti, $i);
+                    }
+
+                    fn disable_interrupt(&mut self, exti: &mut EXTI) {
+                        exti_disable_interrupt(exti, $i);
+                    }
+
+                    fn clear_interrupt_pending_bit(&mut self) {
+                        exti_clear_pending($i);
+                    }
+
+                    fn check_interrupt(&self) -> bool {
+                        exti_check($i)
+                    }
+
+                    fn interrupt(&self) -> Interrupt {
+                        exti_interrupt($i)
+                    }
+                }
+
+                $(#[$attr])*
+                impl<MODE> $PXi<Output<MODE>> {
+                    pub fn set_speed(&mut self, speed: Speed) {
+                        unsafe {
+                            let offset = 4 * ($i & 0b111);
+                            // Change only MODE bits.
+                            (*$GPIOX::ptr()).$CFGR.modify(|r, w| w.bits(r.bits() & !(0b11 << offset) | ((speed as u32) << offset)));
+                        }
+                    }
+                }
+
+                $(#[$attr])*
+                impl<MODE> $PXi<AltOutput<MODE>> {
+                    pub fn set_speed(&mut self, speed: Speed) {
+                        unsafe {
+                            let offset = 4 * ($i & 0b111);
+                            // Change only MODE bits.
+                            (*$GPIOX::ptr()).$CFGR.modify(|r, w| w.bits(r.bits() & !(0b11 << offset) | ((speed as u32) << offset)));
+                        }
+                    }
+                }
+
                 // impl<MODE> OutputPin for $PXi<Output<MODE>> {
                 //     fn set_high(&mut self) {
                 //         unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(1 << $i)) }
@@ -230,6 +1019,7 @@ macro_rules! gpio {
                 // }
 
                 // Impliment embedded-hal gpio
+                $(#[$attr])*
                 impl<MODE> InputPin for $PXi<Input<MODE>> {
                     type Error = Infallible;
 
@@ -246,6 +1036,7 @@ macro_rules! gpio {
                     }
                 }
 
+                $(#[$attr])*
                 impl<MODE> OutputPin for $PXi<Output<MODE>> {
                     type Error = Infallible;
 
@@ -272,6 +1063,7 @@ macro_rules! gpio {
                     }
                 }
 
+                $(#[$attr])*
                 impl<MODE> StatefulOutputPin for $PXi<Output<MODE>> {
                     // type Error = Infallible;
 
@@ -290,24 +1082,44 @@ macro_rules! gpio {
                     }
                 }
 
+                $(#[$attr])*
                 impl<MODE> ToggleableOutputPin for $PXi<Output<MODE>> {
                     type Error = Infallible;
 
+                    // Read OUTDR and write BSHR in a critical section, so an interrupt can not change the pin in between.
                     fn toggle(&mut self) -> Result<(), Self::Error> {
-                        if self.is_set_high().unwrap() {
-                            self.set_low().unwrap();
-                        } else {
-                            self.set_high().unwrap();
-                        }
+                        riscv::interrupt::free(|| unsafe {
+                            let outdr = (*$GPIOX::ptr()).outdr.read().bits();
+                            let mask = 0b1 << $i;
+                            (*$GPIOX::ptr()).bshr.write(|w| w.bits(((outdr & mask) << 16) | (!outdr & mask)));
+                        });
                         Ok(())
                     }
                 }
+
+                $(#[$attr])*
+                impl InputPin for $PXi<Output<OpenDrain>> {
+                    type Error = Infallible;
+
+                    // Read actual level of the line.
+                    fn is_high(&self) -> Result<bool, Self::Error> {
+                        unsafe {
+                            Ok((*$GPIOX::ptr()).indr.read().bits() & (0b1 << $i) > 0)
+                        }
+                    }
+
+                    fn is_low(&self) -> Result<bool, Self::Error> {
+                        unsafe {
+                            Ok((*$GPIOX::ptr()).indr.read().bits() & (0b1 << $i) == 0)
+                        }
+                    }
+                }
             )+
         }
     };
 }
 
-gpio!(GPIOA, gpioa, 0, [
+gpio!(GPIOA, gpioa, 0, PAx, [
     PA0: (pa0, 0, Input<Floating>, cfglr),
     PA1: (pa1, 1, Input<Floating>, cfglr),
     PA2: (pa2, 2, Input<Floating>, cfglr),
@@ -321,17 +1133,17 @@ gpio!(GPIOA, gpioa, 0, [
     PA10: (pa10, 10, Input<Floating>, cfghr),
     PA11: (pa11, 11, Input<Floating>, cfghr), // Connected to USB on CH32V103R8T6-EVT-R1
     PA12: (pa12, 12, Input<Floating>, cfghr), // Connected to USB on CH32V103R8T6-EVT-R1
-    PA13: (pa13, 13, Input<PullUp>, cfghr), // Connected DIO(SWDIO) on CH32V103R8T6-EVT-R1. Can release from WCH_Link
-    PA14: (pa14, 14, Input<PullDown>, cfghr),  // Connected CLK(SWCLK) on CH32V103R8T6-EVT-R1. Can release from WCH_Link
-    PA15: (pa15, 15, Input<PullUp>, cfghr),
+    PA13: (pa13, 13, Debugger, cfghr), // Connected DIO(SWDIO) on CH32V103R8T6-EVT-R1. Release by Afio::disable_swd()
+    PA14: (pa14, 14, Debugger, cfghr),  // Connected CLK(SWCLK) on CH32V103R8T6-EVT-R1. Release by Afio::disable_swd()
+    PA15: (pa15, 15, Debugger, cfghr), // JTDI. Release by Afio::disable_jtag()
 ]);
 
-gpio!(GPIOB, gpiob, 1, [
+gpio!(GPIOB, gpiob, 1, PBx, [
     PB0: (pb0, 0, Input<Floating>, cfglr),
     PB1: (pb1, 1, Input<Floating>, cfglr),
     PB2: (pb2, 2, Input<Floating>, cfglr),
-    PB3: (pb3, 3, Input<Floating>, cfglr),
-    PB4: (pb4, 4, Input<Floating>, cfglr),
+    PB3: (pb3, 3, Debugger, cfglr), // JTDO. Release by Afio::disable_jtag()
+    PB4: (pb4, 4, Debugger, cfglr), // NJTRST. Release by Afio::disable_jtag()
     PB5: (pb5, 5, Input<Floating>, cfglr),
     PB6: (pb6, 6, Input<Floating>, cfglr),
     PB7: (pb7, 7, Input<Floating>, cfglr),
@@ -345,4 +1157,43 @@ gpio!(GPIOB, gpiob, 1, [
     PB15: (pb15, 15, Input<Floating>, cfghr),
 ]);
 
-// GPIOC, GPIOD ...
\ No newline at end of file
+// PC0-PC12 and PD2 are only on 64 pins package (R8T6).
+gpio!(GPIOC, gpioc, 2, PCx, [
+    #[cfg(feature = "r8t6")]
+    PC0: (pc0, 0, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PC1: (pc1, 1, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PC2: (pc2, 2, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PC3: (pc3, 3, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PC4: (pc4, 4, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PC5: (pc5, 5, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PC6: (pc6, 6, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PC7: (pc7, 7, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PC8: (pc8, 8, Input<Floating>, cfghr),
+    #[cfg(feature = "r8t6")]
+    PC9: (pc9, 9, Input<Floating>, cfghr),
+    #[cfg(feature = "r8t6")]
+    PC10: (pc10, 10, Input<Floating>, cfghr),
+    #[cfg(feature = "r8t6")]
+    PC11: (pc11, 11, Input<Floating>, cfghr),
+    #[cfg(feature = "r8t6")]
+    PC12: (pc12, 12, Input<Floating>, cfghr),
+    PC13: (pc13, 13, Input<Floating>, cfghr),
+    PC14: (pc14, 14, Input<Floating>, cfghr), // OSC32_IN
+    PC15: (pc15, 15, Input<Floating>, cfghr), // OSC32_OUT
+]);
+
+// PD0 and PD1 are OSC_IN and OSC_OUT. Can use as GPIO if HSE is not used.
+gpio!(GPIOD, gpiod, 3, PDx, [
+    PD0: (pd0, 0, Input<Floating>, cfglr),
+    PD1: (pd1, 1, Input<Floating>, cfglr),
+    #[cfg(feature = "r8t6")]
+    PD2: (pd2, 2, Input<Floating>, cfglr),
+]);
\ No newline at end of file
@@ -1,10 +1,11 @@
 use embedded_hal::blocking::i2c;
 
-use ch32v1::ch32v103::{ RCC, I2C1 };
-// use crate::time::*;
+use ch32v1::ch32v103::I2C1;
+use crate::time::*;
 use crate::rcc::*;
 use crate::gpio::*;
-use crate::gpio::gpiob::{ PB6, PB7 };
+use crate::gpio::gpiob::{ PB6, PB7, PB8, PB9 };
+use crate::afio::{ Afio, Pins, NoRemap, Remap, RemapMode };
 
 // define I2C error
 #[derive(Debug)]
@@ -23,98 +24,135 @@ pub enum I2cError {
     UnknownError,
 }
 
+// I2C can not be configured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum I2cConfigError {
+    // PCLK1 is out of 2-36MHz.
+    Pclk1(Hertz),
+}
+
+#[derive(Clone, Copy)]
 pub enum I2cMode {
     Standard, // 100kHz
     Fast, // 400kHz
 }
 
-pub trait SclPin<I2C> {
-    fn remap(&self) -> bool;
-}
-pub trait SdaPin<I2C> {
-    fn remap(&self) -> bool;
-}
+// (SCL, SDA)
+impl Pins<I2C1, NoRemap> for (PB6<AltOutput<OpenDrain>>, PB7<AltOutput<OpenDrain>>) {}
 
-impl SclPin<I2C1> for PB6<AltOutput<OpenDrain>> {
-    fn remap(&self) -> bool {
-        false
-    }
-}
-impl SdaPin<I2C1> for PB7<AltOutput<OpenDrain>> {
-    fn remap(&self) -> bool {
-        false
-    }
-}
+// Remap
+impl Pins<I2C1, Remap> for (PB8<AltOutput<OpenDrain>>, PB9<AltOutput<OpenDrain>>) {}
 
 // Serial abstraction
 pub struct I2c<I2C, PINS> {
     i2c: I2C,
     pins: PINS,
+    mode: I2cMode,
 }
 
-impl<SC, SD> I2c<I2C1, (SC, SD)> {
+// FREQ field from PCLK1
+fn i2c1_base_freq(clocks: &Clocks) -> Result<u32, I2cConfigError> {
+    let base_freq = clocks.pclk1().0 / 1000_000; // by MHz
+    if (base_freq < 2) | (base_freq > 36) {
+        return Err(I2cConfigError::Pclk1(clocks.pclk1()));
+    }
+    Ok(base_freq)
+}
+
+// Set FREQ and CCR from PCLK1 in MHz. I2C must be disabled.
+fn i2c1_set_clock(mode: I2cMode, base_freq: u32) {
+    unsafe {
+        (*I2C1::ptr()).ctlr2.modify(|_, w| w.freq().bits(base_freq as u8));
+
+        match mode {
+            // CCR values are referred to STM32F4xx (M0090 Rev 19) datasheet
+            I2cMode::Standard => {
+                // Thigh = CCR * TPCLK1
+                // Tlow = CCR * TPCLK1
+                // (1 / 100kHz) * (1 / 2) = CCR * (1 / PCLK1)
+                let ccr = 5 * base_freq;
+                (*I2C1::ptr()).ckcfgr.modify(|_, w|
+                    w
+                        .f_s()
+                        .clear_bit()
+                        .ccr()
+                        .bits(ccr as u16)
+                );
+            }
+            I2cMode::Fast => {
+                let ccr = if base_freq < 10 {
+                    (*I2C1::ptr()).ckcfgr.modify(|_, w| w.duty().clear_bit());
+
+                    // If DUTY = 0:
+                    // Thigh = CCR * TPCLK1
+                    // Tlow = 2 * CCR * TPCLK1
+                    // (1 / 400kHz) * (1 / 3) = CCR * (1 / PCLK1)
+                    (base_freq * 10) / 12
+                } else {
+                    // If DUTY = 1:
+                    (*I2C1::ptr()).ckcfgr.modify(|_, w| w.duty().set_bit());
+
+                    // Thigh = 9 * CCR * TPCLK1
+                    // Tlow = 16 * CCR * TPCLK1
+                    // (1 / 400kHz) * (9 /25) = 9 * CCR * (1 / PCLK1)
+                    // base_freq must be >10HHz
+                    base_freq / 10
+                };
+                (*I2C1::ptr()).ckcfgr.modify(|_, w|
+                    w
+                        .f_s()
+                        .set_bit()
+                        .ccr()
+                        .bits(ccr as u16)
+                );
+            }
+        }
+    }
+}
+
+impl<PINS> I2c<I2C1, PINS> {
     // init I2C1
+    // Remap is selected by the pins.
     // TODO: I2c::new() for I2C1 and I2C2
-    pub fn i2c1(i2c: I2C1, pins: (SC, SD), mode: I2cMode, clocks: &Clocks) -> Self
-        where SC: SclPin<I2C1>, SD: SdaPin<I2C1>
+    pub fn i2c1<REMAP>(
+        i2c: I2C1,
+        pins: PINS,
+        afio: &mut Afio,
+        apb1: &mut APB1,
+        mode: I2cMode,
+        clocks: &Clocks
+    ) -> Result<Self, I2cConfigError>
+        where PINS: Pins<I2C1, REMAP>, REMAP: RemapMode
     {
+        let base_freq = i2c1_base_freq(clocks)?;
+
+        afio.remap::<I2C1, REMAP>();
+        I2C1::enable(apb1);
+        I2C1::reset(apb1);
+
         unsafe {
-            (*RCC::ptr()).apb1pcenr.modify(|_, w| w.i2c1en().set_bit());
-            let base_freq = clocks.pclk1().0 / 1000_000; // by MHz
-            assert!((base_freq >= 2) & (base_freq <= 36));
-            (*I2C1::ptr()).ctlr2.modify(|_, w| w.freq().bits(base_freq as u8));
-
-            match mode {
-                // CCR values are referred to STM32F4xx (M0090 Rev 19) datasheet
-                I2cMode::Standard => {
-                    // Thigh = CCR * TPCLK1
-                    // Tlow = CCR * TPCLK1
-                    // (1 / 100kHz) * (1 / 2) = CCR * (1 / PCLK1)
-                    let ccr = 5 * base_freq;
-                    (*I2C1::ptr()).ckcfgr.modify(|_, w|
-                        w
-                            .f_s()
-                            .clear_bit()
-                            .ccr()
-                            .bits(ccr as u16)
-                    );
-                }
-                I2cMode::Fast => {
-                    let ccr = if base_freq < 10 {
-                        (*I2C1::ptr()).ckcfgr.modify(|_, w| w.duty().clear_bit());
-
-                        // If DUTY = 0:
-                        // Thigh = CCR * TPCLK1
-                        // Tlow = 2 * CCR * TPCLK1
-                        // (1 / 400kHz) * (1 / 3) = CCR * (1 / PCLK1)
-                        (base_freq * 10) / 12
-                    } else {
-                        // If DUTY = 1:
-                        (*I2C1::ptr()).ckcfgr.modify(|_, w| w.duty().set_bit());
-
-                        // Thigh = 9 * CCR * TPCLK1
-                        // Tlow = 16 * CCR * TPCLK1
-                        // (1 / 400kHz) * (9 /25) = 9 * CCR * (1 / PCLK1)
-                        // base_freq must be >10HHz
-                        base_freq / 10
-                    };
-                    (*I2C1::ptr()).ckcfgr.modify(|_, w|
-                        w
-                            .f_s()
-                            .set_bit()
-                            .ccr()
-                            .bits(ccr as u16)
-                    );
-                }
-            }
+            i2c1_set_clock(mode, base_freq);
 
             // Enable I2C
             (*I2C1::ptr()).ctlr1.modify(|_, w| w.pe().set_bit());
         }
-        Self {
+        Ok(Self {
             i2c: i2c,
             pins: pins,
+            mode: mode,
+        })
+    }
+
+    // Recompute FREQ and CCR after CFGR::reconfigure().
+    // Registers are not changed if the new PCLK1 is out of range.
+    pub fn reconfigure(&mut self, clocks: &Clocks) -> Result<(), I2cConfigError> {
+        let base_freq = i2c1_base_freq(clocks)?;
+        unsafe {
+            (*I2C1::ptr()).ctlr1.modify(|_, w| w.pe().clear_bit());
+            i2c1_set_clock(self.mode, base_freq);
+            (*I2C1::ptr()).ctlr1.modify(|_, w| w.pe().set_bit());
         }
+        Ok(())
     }
 
     pub fn status(&self) -> u16 {
@@ -1,7 +1,15 @@
 #![no_std]
 
+#[cfg(any(
+    all(feature = "c6t6", feature = "c8t6"),
+    all(feature = "c6t6", feature = "r8t6"),
+    all(feature = "c8t6", feature = "r8t6"),
+))]
+compile_error!("Select only one chip package feature: c6t6, c8t6 or r8t6");
+
 pub mod prelude;
 pub mod gpio;
+pub mod afio;
 pub mod rcc;
 pub mod serial;
 pub mod spi;
@@ -1,62 +1,41 @@
 // use core::convert::Infallible;
-use ch32v1::ch32v103::{ RCC, EXTEND, RTC, PWR };
+use core::marker::PhantomData;
+use ch32v1::ch32v103::{ RCC, EXTEND, RTC, PWR, FLASH, BKP };
 use crate::time::*;
+use crate::gpio::{ AltOutput, PushPull };
+use crate::gpio::gpioa::PA8;
+use crate::delay::Delay;
 
-// pub struct AHB;
-// pub struct APB1;
-// pub struct APB2;
+mod enable;
+mod solver;
+pub use enable::*;
+pub use solver::*;
+use solver::{ HSI, HPRE_DIVS, PPRE_DIVS, ADCPRE_DIVS, Rtc, divider, timer_clock, usb_clock };
 
 pub struct Rcc {
-    // pub ahb: AHB,
-    // pub apb1: APB1,
-    // pub apb2: APB2,
+    pub ahb: AHB,
+    pub apb1: APB1,
+    pub apb2: APB2,
     pub cfgr: CFGR,
 }
 
-const HSI: u32 = 8_000_000; // Hz
 const LSI: u32 = 40_000; // Hz
-
-enum Sysclk {
-    Hsi,
-    Hse,
-    Pll,
-}
-
-pub enum PllClkSrc {
-    Hsi,
-    // supported in extend_ctr
-    HsiDiv2,
-    Hse,
-    HseDiv2,
-}
-
-enum Rtc {
-    Lsi,
-    Lse,
-    HseDiv128,
-}
-
-// Clock configuration
-pub struct CFGR {
-    hse_freq: Option<u32>,
-    hse_bypass: bool,
-    pll_source: Option<PllClkSrc>,
-    pll_freq: Option<u32>,
-    sysclk_source: Option<Sysclk>,
-    sysclk: Option<u32>,
-    hclk_freq: Option<u32>,
-    pclk1_freq: Option<u32>,
-    pclk2_freq: Option<u32>,
-    lse_freq: Option<u32>,
-    lse_bypass: bool,
-    rtc_source: Option<Rtc>,
-}
+// Loop count to wait HSE and PLL ready.
+const STARTUP_TIMEOUT: u32 = 0x5000;
+// RTC ticks to measure HSI.
+const CALIBRATION_TICKS: u32 = 100;
+// HSITRIM changes HSI about 40kHz by step.
+const HSI_TRIM_STEP: i32 = 40_000;
 
 struct ClockConfig {
     use_hsi: bool,
     use_hse: bool,
     use_pll: bool,
     sysclk: Sysclk,
+    flash_latency: u8,
+    rtc_clk: Option<Hertz>,
+    // measured by calibrate_hsi()
+    hsi_freq: u32,
 }
 
 static mut CLK_CFG: ClockConfig = ClockConfig {
@@ -64,14 +43,26 @@ static mut CLK_CFG: ClockConfig = ClockConfig {
     use_hse: false,
     use_pll: false,
     sysclk: Sysclk::Hsi,
+    flash_latency: 0,
+    rtc_clk: None,
+    hsi_freq: HSI,
 };
 
+// Called with the new clocks when HSE failed and SYSCLK fell back to HSI.
+static mut CSS_HOOK: Option<fn(&Clocks)> = None;
+
+#[derive(Clone, Copy)]
 pub struct Clocks {
     sysclk: Hertz,
     hclk: Hertz,
     pclk1: Hertz,
     pclk2: Hertz,
+    adcclk: Hertz,
+    pclk1_tim: Hertz,
+    pclk2_tim: Hertz,
+    usbclk: Option<Hertz>,
     rtc_clk: Option<Hertz>,
+    hsi_fallback: bool,
 }
 
 pub trait RccExt {
@@ -89,220 +80,370 @@ impl RccExt for RCC {
         // }
 
         Rcc {
-            // ahb: AHB {},
-            // apb1: APB1 {},
-            // apb2: APB2 {},
-            cfgr: CFGR {
-                hse_freq: None,
-                hse_bypass: false,
-                pll_source: None,
-                pll_freq: None,
-                sysclk_source: None,
-                sysclk: None,
-                hclk_freq: None,
-                pclk1_freq: None,
-                pclk2_freq: None,
-                lse_freq: None,
-                lse_bypass: false,
-                rtc_source: None,
-            },
+            ahb: AHB::new(),
+            apb1: APB1::new(),
+            apb2: APB2::new(),
+            cfgr: CFGR::new(),
         }
     }
 }
 
-impl CFGR {
-    pub fn bypass_hse_oscillator(mut self) -> Self {
-        self.hse_bypass = true;
-        self
+// Wait until ready() returns true. Return false if timed out.
+fn wait_ready<F>(ready: F) -> bool where F: Fn() -> bool {
+    for _ in 0..STARTUP_TIMEOUT {
+        if ready() {
+            return true;
+        }
     }
+    false
+}
 
-    pub fn use_hsi(mut self) -> Self {
-        self.sysclk_source = Some(Sysclk::Hsi);
-        self
-    }
+// Set the function called when HSE fails and SYSCLK falls back to HSI.
+// The hook runs in the NMI handler; reconfigure drivers with the new clocks there.
+pub fn set_css_hook(hook: fn(&Clocks)) {
+    riscv::interrupt::free(|| unsafe {
+        CSS_HOOK = Some(hook);
+    });
+}
 
-    pub fn use_hse(mut self, freq: Hertz) -> Self {
-        self.sysclk_source = Some(Sysclk::Hse);
-        self.hse_freq = Some(freq.0);
-        self
+// Call from the NMI handler. Clock security system raises NMI on HSE failure.
+pub fn css_interrupt() {
+    unsafe {
+        // CSSF
+        if (*RCC::ptr()).intr.read().bits() & (0b1 << 7) == 0 {
+            return;
+        }
+        // Clear CSSF by CSSC with keeping interrupt enable bits.
+        (*RCC::ptr()).intr.modify(|r, w| w.bits((r.bits() & 0x1f00) | (0b1 << 23)));
     }
+    hse_failed();
+}
 
-    pub fn use_pll(mut self, freq: Hertz, src: PllClkSrc) -> Self {
-        assert!(freq.0 <= 72_000_000);
-        self.sysclk_source = Some(Sysclk::Pll);
-        self.pll_source = Some(src);
-        self.pll_freq = Some(freq.0);
-        self
-    }
+// HSE stopped and hardware or software switched SYSCLK to HSI.
+fn hse_failed() {
+    unsafe {
+        (*RCC::ptr()).ctlr.modify(|_, w| w.csson().clear_bit().pllon().clear_bit().hseon().clear_bit());
+
+        CLK_CFG.use_hsi = true;
+        CLK_CFG.use_hse = false;
+        CLK_CFG.use_pll = false;
+        CLK_CFG.sysclk = Sysclk::Hsi;
+        // Decrease wait states after lowering SYSCLK.
+        CLK_CFG.flash_latency = flash_latency(HSI.hz());
+        write_flash_latency(CLK_CFG.flash_latency);
+
+        // RTC stops with HSE if clocked by HSE/128.
+        if (*RCC::ptr()).bdctlr.read().rtcsel().bits() == 0b11 {
+            CLK_CFG.rtc_clk = None;
+        }
 
-    pub fn hclk(mut self, freq: Hertz) -> Self {
-        self.hclk_freq = Some(freq.0);
-        self
+        // Prescalers are not changed by hardware.
+        let cfgr0 = (*RCC::ptr()).cfgr0.read();
+        let hclk = HSI / divider(cfgr0.hpre().bits(), &HPRE_DIVS);
+        let pclk1 = hclk / divider(cfgr0.ppre1().bits(), &PPRE_DIVS);
+        let pclk2 = hclk / divider(cfgr0.ppre2().bits(), &PPRE_DIVS);
+        let clocks = Clocks {
+            sysclk: HSI.hz(),
+            hclk: hclk.hz(),
+            pclk1: pclk1.hz(),
+            pclk2: pclk2.hz(),
+            adcclk: (pclk2 / divider(cfgr0.adcpre().bits(), &ADCPRE_DIVS)).hz(),
+            pclk1_tim: timer_clock(pclk1, cfgr0.ppre1().bits()).hz(),
+            pclk2_tim: timer_clock(pclk2, cfgr0.ppre2().bits()).hz(),
+            usbclk: usb_clock(),
+            rtc_clk: CLK_CFG.rtc_clk,
+            hsi_fallback: true,
+        }.rescale_hsi(HSI, CLK_CFG.hsi_freq);
+
+        if let Some(hook) = CSS_HOOK {
+            hook(&clocks);
+        }
     }
+}
 
-    pub fn pclk1(mut self, freq: Hertz) -> Self {
-        self.pclk1_freq = Some(freq.0);
-        self
-    }
+// Error of HSI calibration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationError {
+    // RTC is not clocked by LSE.
+    LseNotUsed,
+    // HCLK is not made from HSI.
+    HsiNotUsed,
+}
 
-    pub fn pclk2(mut self, freq: Hertz) -> Self {
-        self.pclk2_freq = Some(freq.0);
-        self
+fn read_hsi_trim() -> u8 {
+    unsafe { (((*RCC::ptr()).ctlr.read().bits() >> 3) & 0b1_1111) as u8 }
+}
+
+fn write_hsi_trim(trim: u8) {
+    unsafe {
+        // HSITRIM[4:0] is CTLR[7:3]
+        (*RCC::ptr()).ctlr.modify(|r, w|
+            w.bits((r.bits() & !(0b1_1111 << 3)) | (((trim as u32) & 0b1_1111) << 3))
+        );
     }
+}
+
+// RTC prescaler to count every 1ms.
+fn rtc_prescale(rtc_clk: Hertz) -> u32 {
+    // make 1s
+    // let prescale: u32 = rtc_clk.0 - 1;
+    // make 1ms
+    (rtc_clk.0 + 500) / 1000 - 1
+}
+
+// Measure HSI by counting HCLK/8 for CALIBRATION_TICKS of RTC clocked by LSE.
+fn measure_hsi(nominal_hclk: u32, lse: Hertz, delay: &mut Delay) -> u32 {
+    let wait_tick = || unsafe {
+        (*RTC::ptr()).ctlrl.modify(|_, w| w.secf().clear_bit());
+        while (*RTC::ptr()).ctlrl.read().secf().bit_is_clear() {}
+    };
+
+    // synchronize to RTC tick
+    wait_tick();
+    let count = delay.count_cycles(|| {
+        for _ in 0..CALIBRATION_TICKS {
+            wait_tick();
+        }
+    });
 
-    pub fn bypass_lse_oscillator(mut self) -> Self {
-        self.lse_bypass = true;
-        self
+    // measured HCLK = count * 8 / (CALIBRATION_TICKS * (prescale + 1) / LSE)
+    let period = (CALIBRATION_TICKS as u64) * ((rtc_prescale(lse) + 1) as u64);
+    let hclk = ((count as u64) * 8 * (lse.0 as u64)) / period;
+    (((HSI as u64) * hclk) / (nominal_hclk as u64)) as u32
+}
+
+// Measure HSI against LSE and adjust HSITRIM.
+// Return clocks with the calibrated HSI; call reconfigure() of drivers including delay.
+// RTC must be clocked by LSE with CFGR::use_lse().
+pub fn calibrate_hsi(clocks: &Clocks, delay: &mut Delay) -> Result<Clocks, CalibrationError> {
+    let lse = match clocks.rtc_clk() {
+        Some(freq) if unsafe { (*RCC::ptr()).bdctlr.read().rtcsel().bits() } == 0b01 => freq,
+        _ => {
+            return Err(CalibrationError::LseNotUsed);
+        }
+    };
+
+    let uses_hsi = unsafe {
+        match CLK_CFG.sysclk {
+            Sysclk::Hsi => true,
+            Sysclk::Hse => false,
+            // PLLSRC is 0 for HSI or HSI/2.
+            Sysclk::Pll => (*RCC::ptr()).cfgr0.read().pllsrc().bit_is_clear(),
+        }
+    };
+    if !uses_hsi {
+        return Err(CalibrationError::HsiNotUsed);
     }
 
-    pub fn use_lsi(mut self) -> Self {
-        self.rtc_source = Some(Rtc::Lsi);
-        self
+    let old_hsi = unsafe { CLK_CFG.hsi_freq };
+    let nominal_hclk = (((clocks.hclk().0 as u64) * (HSI as u64)) / (old_hsi as u64)) as u32;
+
+    let mut trim = read_hsi_trim() as i32;
+    let mut best: Option<(i32, u32)> = None;
+    for _ in 0..4 {
+        let hsi = measure_hsi(nominal_hclk, lse, delay);
+        best = match best {
+            Some((_, best_hsi)) if best_hsi.abs_diff(HSI) <= hsi.abs_diff(HSI) => best,
+            _ => Some((trim, hsi)),
+        };
+
+        // round to the nearest step
+        let error = (HSI as i32) - (hsi as i32);
+        let step = (error + (error.signum() * HSI_TRIM_STEP) / 2) / HSI_TRIM_STEP;
+        let next = (trim + step).clamp(0, 0b1_1111);
+        if next == trim {
+            break;
+        }
+        trim = next;
+        write_hsi_trim(trim as u8);
     }
 
-    pub fn use_lse(mut self, freq: Hertz) -> Self {
-        self.rtc_source = Some(Rtc::Lse);
-        self.lse_freq = Some(freq.0);
-        self
+    let (trim, hsi) = best.unwrap();
+    write_hsi_trim(trim as u8);
+    unsafe {
+        CLK_CFG.hsi_freq = hsi;
     }
 
-    pub fn freeze(mut self) -> Clocks {
-        if self.hse_freq.is_some() {
-            // check HSE range
-            if self.hse_bypass {
-                assert!(self.hse_freq.unwrap() <= (25).mhz().0);
+    Ok(clocks.rescale_hsi(old_hsi, hsi))
+}
 
-                unsafe {
-                    (*RCC::ptr()).ctlr.modify(|_, w| w.hsebyp().set_bit());
-                }
-            } else {
-                assert!(
-                    (self.hse_freq.unwrap() >= (4).mhz().0) &
-                        (self.hse_freq.unwrap() <= (16).mhz().0)
-                );
+fn read_flash_latency() -> u8 {
+    unsafe { ((*FLASH::ptr()).actlr.read().bits() & 0b111) as u8 }
+}
 
-                unsafe {
-                    (*RCC::ptr()).ctlr.modify(|_, w| w.hsebyp().clear_bit().hseon().set_bit());
-                    while !(*RCC::ptr()).ctlr.read().hserdy().bit_is_set() {}
-                }
-            }
+fn write_flash_latency(latency: u8) {
+    unsafe {
+        // LATENCY[2:0] and enable prefetch buffer (PRFTBE, bit 4)
+        (*FLASH::ptr()).actlr.modify(|r, w|
+            w.bits((r.bits() & !0b111) | (latency as u32) | (0b1 << 4))
+        );
+        while read_flash_latency() != latency {}
+    }
+}
 
-            // setup HSE
-        } else {
+impl CFGR {
+    // Set SYSCLK and prescalers. Return the clock tree and if fell back to HSI.
+    fn set_clock_tree(&self) -> Result<(ClockTree, bool), ClockError> {
+        let mut tree = self.solve()?;
+        let mut hsi_fallback = false;
+
+        unsafe {
+            CLK_CFG.use_hsi = false;
+            CLK_CFG.use_hse = false;
+            CLK_CFG.use_pll = false;
+
+            // Run on HSI while changing the clock tree. PLL can be configured only when off.
+            (*RCC::ptr()).ctlr.modify(|_, w| w.hsion().set_bit().csson().clear_bit());
+            while !(*RCC::ptr()).ctlr.read().hsirdy().bit_is_set() {}
+            (*RCC::ptr()).cfgr0.modify(|_, w| w.sw().bits(0));
+            while (*RCC::ptr()).cfgr0.read().sws().bits() != 0 {}
+            (*RCC::ptr()).ctlr.modify(|_, w| w.pllon().clear_bit());
         }
 
-        match self.sysclk_source {
-            Some(Sysclk::Hsi) => {
-                unsafe {
-                    CLK_CFG.use_hsi = true;
-                    CLK_CFG.sysclk = Sysclk::Hsi;
-                    (*RCC::ptr()).cfgr0.modify(|_, w| w.sw().bits(0));
+        if self.hse_freq.is_some() {
+            // setup HSE
+            unsafe {
+                if self.hse_bypass {
+                    (*RCC::ptr()).ctlr.modify(|_, w| w.hseon().clear_bit());
+                    (*RCC::ptr()).ctlr.modify(|_, w| w.hsebyp().set_bit());
+                } else {
+                    (*RCC::ptr()).ctlr.modify(|_, w| w.hsebyp().clear_bit());
                 }
-                self.sysclk = Some(HSI);
+                (*RCC::ptr()).ctlr.modify(|_, w| w.hseon().set_bit());
             }
-            Some(Sysclk::Hse) => {
+            if !wait_ready(|| unsafe { (*RCC::ptr()).ctlr.read().hserdy().bit_is_set() }) {
+                // Crystal failed. Stop HSE and fall back to HSI.
                 unsafe {
-                    CLK_CFG.use_hse = true;
-                    CLK_CFG.sysclk = Sysclk::Hse;
-                    (*RCC::ptr()).cfgr0.modify(|_, w| w.sw().bits(0b1));
+                    (*RCC::ptr()).ctlr.modify(|_, w| w.hseon().clear_bit());
                 }
-                self.sysclk = self.hse_freq;
-            }
-            Some(Sysclk::Pll) => {
-                unsafe {
-                    CLK_CFG.use_pll = true;
-                    CLK_CFG.sysclk = Sysclk::Pll;
+                if tree.uses_hse() {
+                    tree = self.fallback_tree();
+                    hsi_fallback = true;
                 }
-                let mut pll_base_freq = HSI;
-                match self.pll_source {
-                    Some(PllClkSrc::Hsi) => {
-                        unsafe {
-                            CLK_CFG.use_hsi = true;
-                            // use HSI/2 as Pll clock source. Reset only on power reset
-                            (*EXTEND::ptr()).extend_ctr.modify(|_, w| w.hsipre().set_bit());
-                            // use HIS or HSI/2 as Pll clock source
-                            (*RCC::ptr()).cfgr0.modify(|_, w| w.pllsrc().clear_bit());
-                        }
-                    }
-                    Some(PllClkSrc::HsiDiv2) => {
-                        pll_base_freq = HSI / 2;
-                        unsafe {
-                            CLK_CFG.use_hsi = true;
-                            // use HSI/2 as Pll clock source. Reset only on power reset
-                            (*EXTEND::ptr()).extend_ctr.modify(|_, w| w.hsipre().clear_bit());
-                            // use HIS or HSI/2 as Pll clock source
-                            (*RCC::ptr()).cfgr0.modify(|_, w| w.pllsrc().clear_bit());
-                        }
+            }
+        }
+
+        if let Some(pll_source) = tree.pll_source {
+            unsafe {
+                CLK_CFG.use_pll = true;
+                match pll_source {
+                    PllClkSrc::Hsi => {
+                        CLK_CFG.use_hsi = true;
+                        // use HSI as Pll clock source. Reset only on power reset
+                        (*EXTEND::ptr()).extend_ctr.modify(|_, w| w.hsipre().set_bit());
+                        // use HIS or HSI/2 as Pll clock source
+                        (*RCC::ptr()).cfgr0.modify(|_, w| w.pllsrc().clear_bit());
                     }
-                    Some(PllClkSrc::Hse) => {
-                        assert!(self.hse_freq.is_some());
-                        pll_base_freq = self.hse_freq.unwrap();
-                        unsafe {
-                            CLK_CFG.use_hse = true;
-                            (*RCC::ptr()).cfgr0.modify(|_, w|
-                                w.pllsrc().set_bit().pllxtpre().clear_bit()
-                            );
-                        }
+                    PllClkSrc::HsiDiv2 => {
+                        CLK_CFG.use_hsi = true;
+                        // use HSI/2 as Pll clock source. Reset only on power reset
+                        (*EXTEND::ptr()).extend_ctr.modify(|_, w| w.hsipre().clear_bit());
+                        // use HIS or HSI/2 as Pll clock source
+                        (*RCC::ptr()).cfgr0.modify(|_, w| w.pllsrc().clear_bit());
                     }
-                    Some(PllClkSrc::HseDiv2) => {
-                        assert!(self.hse_freq.is_some());
-                        pll_base_freq = self.hse_freq.unwrap() / 2;
-                        unsafe {
-                            CLK_CFG.use_hse = true;
-                            (*RCC::ptr()).cfgr0.modify(|_, w|
-                                w.pllsrc().set_bit().pllxtpre().set_bit()
-                            );
-                        }
+                    PllClkSrc::Hse => {
+                        CLK_CFG.use_hse = true;
+                        (*RCC::ptr()).cfgr0.modify(|_, w|
+                            w.pllsrc().set_bit().pllxtpre().clear_bit()
+                        );
                     }
-                    None => {
-                        // pll_source must be set if Pll.
-                        unreachable!();
+                    PllClkSrc::HseDiv2 => {
+                        CLK_CFG.use_hse = true;
+                        (*RCC::ptr()).cfgr0.modify(|_, w|
+                            w.pllsrc().set_bit().pllxtpre().set_bit()
+                        );
                     }
                 }
 
                 // setup PLL
-                let pll_multi = self.pll_freq.unwrap() / pll_base_freq;
-                assert!(pll_multi > 1);
-                // USB must 48MHz
-                let pll_freq = pll_base_freq * pll_multi;
-                #[cfg(feature = "usbhd")]
-                assert!((pll_freq == 48_000_000) | (pll_freq == 72_000_000));
+                (*RCC::ptr()).cfgr0.modify(|_, w| w.pllmul().bits((tree.pll_multi as u8) - 2));
+                (*RCC::ptr()).ctlr.modify(|_, w| w.pllon().set_bit());
+            }
+            if !wait_ready(|| unsafe { (*RCC::ptr()).ctlr.read().pllrdy().bit_is_set() }) {
+                // PLL failed to lock. Stop PLL and fall back to HSI.
                 unsafe {
-                    (*RCC::ptr()).cfgr0.modify(|_, w| w.pllmul().bits((pll_multi as u8) - 2));
-                    // (*RCC::ptr()).cfgr0.modify(|_, w| w.pllmul().bits(0b110));
-                    (*RCC::ptr()).ctlr.modify(|_, w| w.pllon().set_bit());
-                    while !(*RCC::ptr()).ctlr.read().pllrdy().bit_is_set() {}
-                    (*RCC::ptr()).cfgr0.modify(|_, w| w.sw().bits(0b10));
-                    // For USBHD
-                    #[cfg(feature = "usbhd")]
-                    if pll_freq == 48_000_000 {
-                        (*RCC::ptr()).cfgr0.modify(|_, w| w.usbpre().set_bit());
-                    } else {
-                        (*RCC::ptr()).cfgr0.modify(|_, w| w.usbpre().clear_bit());
-                    }
+                    (*RCC::ptr()).ctlr.modify(|_, w| w.pllon().clear_bit());
+                    CLK_CFG.use_pll = false;
+                    CLK_CFG.use_hse = false;
                 }
-                self.sysclk = Some(pll_freq);
+                tree = self.fallback_tree();
+                hsi_fallback = true;
             }
-            None => {
-                // use default HSI
+            // For USBHD
+            #[cfg(feature = "usbhd")]
+            unsafe {
+                if tree.sysclk.0 == 48_000_000 {
+                    (*RCC::ptr()).cfgr0.modify(|_, w| w.usbpre().set_bit());
+                } else {
+                    (*RCC::ptr()).cfgr0.modify(|_, w| w.usbpre().clear_bit());
+                }
+            }
+        }
+
+        // Set prescalers before switching to the new SYSCLK.
+        unsafe {
+            (*RCC::ptr()).cfgr0.modify(|_, w|
+                w
+                    .hpre()
+                    .bits(tree.hpre_bits)
+                    .ppre1()
+                    .bits(tree.ppre1_bits)
+                    .ppre2()
+                    .bits(tree.ppre2_bits)
+                    .adcpre()
+                    .bits(tree.adcpre_bits)
+            );
+        }
+
+        // Increase wait states before raising SYSCLK.
+        let latency = flash_latency(tree.sysclk);
+        if latency > read_flash_latency() {
+            write_flash_latency(latency);
+        }
+
+        let sw_bits = match tree.sysclk_source {
+            Sysclk::Hsi => {
                 unsafe {
                     CLK_CFG.use_hsi = true;
-                    CLK_CFG.sysclk = Sysclk::Hsi;
-                    (*RCC::ptr()).cfgr0.modify(|_, w| w.sw().bits(0));
                 }
-                self.sysclk = Some(HSI);
+                0b00
+            }
+            Sysclk::Hse => {
+                unsafe {
+                    CLK_CFG.use_hse = true;
+                }
+                0b01
+            }
+            Sysclk::Pll => { 0b10 }
+        };
+        unsafe {
+            CLK_CFG.sysclk = tree.sysclk_source;
+            CLK_CFG.flash_latency = latency;
+            (*RCC::ptr()).cfgr0.modify(|_, w| w.sw().bits(sw_bits));
+            while (*RCC::ptr()).cfgr0.read().sws().bits() != sw_bits {}
+        }
+        // Decrease wait states after lowering SYSCLK.
+        write_flash_latency(latency);
+
+        if self.css & !hsi_fallback {
+            unsafe {
+                if CLK_CFG.use_hse {
+                    // CSS works only while HSE is on.
+                    (*RCC::ptr()).ctlr.modify(|_, w| w.csson().set_bit());
+                }
             }
         }
 
         // USBHD use PLL
         #[cfg(feature = "usbhd")]
-        match self.sysclk_source {
-            Some(Sysclk::Pll) => {} // ignore; already finished
+        match tree.sysclk_source {
+            Sysclk::Pll => {} // ignore; already finished
             _ => {
                 unsafe {
                     CLK_CFG.use_hsi = true;
                     CLK_CFG.use_pll = true;
                     // USB: 48MHz, HIS: 8MHz => PLL output = HSI x 6
+                    (*EXTEND::ptr()).extend_ctr.modify(|_, w| w.hsipre().set_bit());
                     (*RCC::ptr()).cfgr0.modify(|_, w|
                         w.pllsrc().clear_bit().pllmul().bits(0b100).usbpre().set_bit()
                     );
@@ -312,10 +453,22 @@ impl CFGR {
             }
         }
 
+        unsafe {
+            // Stop unused HSE. RTC may use HSE/128.
+            if !CLK_CFG.use_hse & !matches!(self.rtc_source, Some(Rtc::HseDiv128)) {
+                (*RCC::ptr()).ctlr.modify(|_, w| w.hseon().clear_bit());
+            }
+        }
+
+        Ok((tree, hsi_fallback))
+    }
+
+    fn setup_rtc(&self) -> Option<Hertz> {
         // Setup RTC clock
         unsafe {
             // supply clocks to the ppower and backup interface modules.
-            (*RCC::ptr()).apb1pcenr.modify(|_, w| w.pwren().set_bit().bkpen().set_bit());
+            PWR::enable_unchecked();
+            BKP::enable_unchecked();
             // enable editing backup area
             (*PWR::ptr()).ctlr.modify(|_, w| w.dbp().set_bit());
         }
@@ -345,12 +498,21 @@ impl CFGR {
                 Some(self.lse_freq.unwrap().hz())
             }
             Some(Rtc::HseDiv128) => {
-                // TODO: Hse need to on if not using HSE as sysclk source.
-                unsafe {
-                    (*RCC::ptr()).bdctlr.modify(|_, w| w.rtcsel().bits(0b11));
+                // HSE is already on if started successfully.
+                if unsafe { (*RCC::ptr()).ctlr.read().hserdy().bit_is_set() } {
+                    unsafe {
+                        // keep HSE on after Stop mode.
+                        CLK_CFG.use_hse = true;
+                        (*RCC::ptr()).bdctlr.modify(|_, w| w.rtcsel().bits(0b11));
+                    }
+                    Some((self.hse_freq.unwrap() / 128).hz())
+                } else {
+                    // RTC off
+                    unsafe {
+                        (*RCC::ptr()).bdctlr.modify(|_, w| w.rtcsel().bits(0b00));
+                    }
+                    None
                 }
-                // TODO: set HSE / 128
-                None
             }
             None => {
                 // RTC off
@@ -363,10 +525,7 @@ impl CFGR {
 
         if rtc_clk.is_some() {
             // Setup RTC
-            // make 1s
-            // let prescale: u32 = rtc_clk.unwrap().0 - 1;
-            // make 1ms
-            let prescale: u32 = (rtc_clk.unwrap().0 + 500) / 1000 - 1;
+            let prescale: u32 = rtc_prescale(rtc_clk.unwrap());
             unsafe {
                 // start RTC before setting values.
                 // Can't get the RTC to work
@@ -398,69 +557,43 @@ impl CFGR {
         if rtc_clk.is_none() {
             // stop clocks to PWR if no RTC clock source.
             unsafe {
-                (*RCC::ptr()).apb1pcenr.modify(|_, w| w.pwren().clear_bit());
+                PWR::disable_unchecked();
             }
         }
 
-        let sysclk = self.sysclk.unwrap();
-
-        let (hpre_bits, hclk) = if self.hclk_freq.is_some() {
-            match sysclk / self.hclk_freq.unwrap() {
-                0..=1 => (0b0000, sysclk),
-                2 => (0b1000, sysclk / 2),
-                3..=6 => (0b1001, sysclk / 4),
-                7..=11 => (0b1010, sysclk / 8),
-                12..=31 => (0b1011, sysclk / 16),
-                32..=90 => (0b1100, sysclk / 64),
-                91..=181 => (0b1101, sysclk / 128),
-                182..=362 => (0b1110, sysclk / 256),
-                _ => (0b1111, sysclk / 512),
-            }
-        } else {
-            (0b0000, sysclk)
-        };
-        unsafe {
-            (*RCC::ptr()).cfgr0.modify(|_, w| w.hpre().bits(hpre_bits));
-        }
+        rtc_clk
+    }
 
-        // APB1
-        let (ppre1_bits, pclk1) = if self.pclk1_freq.is_some() {
-            match hclk / self.pclk1_freq.unwrap() {
-                0..=1 => (0b000, hclk),
-                2 => (0b100, hclk / 2),
-                3..=6 => (0b101, hclk / 4),
-                7..=11 => (0b110, hclk / 8),
-                _ => (0b111, hclk / 16),
-            }
-        } else {
-            (0b000, hclk)
-        };
+    // If HSE or PLL does not start, SYSCLK falls back to HSI.
+    // Check it by Clocks::hsi_fallback().
+    pub fn freeze(self) -> Result<Clocks, ClockError> {
+        let (tree, hsi_fallback) = self.set_clock_tree()?;
+        let rtc_clk = self.setup_rtc();
         unsafe {
-            (*RCC::ptr()).cfgr0.modify(|_, w| w.ppre1().bits(ppre1_bits));
+            CLK_CFG.rtc_clk = rtc_clk;
         }
 
-        // APB2
-        let (ppre2_bits, pclk2) = if self.pclk2_freq.is_some() {
-            match hclk / self.pclk2_freq.unwrap() {
-                0..=1 => (0b000, hclk),
-                2 => (0b100, hclk / 2),
-                3..=6 => (0b101, hclk / 4),
-                7..=11 => (0b110, hclk / 8),
-                _ => (0b111, hclk / 16),
-            }
-        } else {
-            (0b000, hclk)
-        };
+        Ok(Clocks::from_tree(&tree, rtc_clk, hsi_fallback))
+    }
+
+    // Change SYSCLK and prescalers at runtime. RTC set by freeze() is kept running.
+    // Call reconfigure() of every driver with the returned clocks.
+    //
+    //  let fast = rcc.cfgr.use_pll((72).mhz(), PllClkSrc::Hsi);
+    //  let idle = fast.clone().use_hsi();
+    //  let clocks = fast.clone().freeze().unwrap();
+    //  ...
+    //  let clocks = idle.reconfigure().unwrap();
+    //  delay.reconfigure(&clocks);
+    pub fn reconfigure(&self) -> Result<Clocks, ClockError> {
+        let (tree, hsi_fallback) = self.set_clock_tree()?;
         unsafe {
-            (*RCC::ptr()).cfgr0.modify(|_, w| w.ppre2().bits(ppre2_bits));
-        }
+            if let Some(Rtc::HseDiv128) = self.rtc_source {
+                // keep HSE on after Stop mode.
+                CLK_CFG.use_hse = (*RCC::ptr()).ctlr.read().hserdy().bit_is_set();
+            }
 
-        Clocks {
-            sysclk: sysclk.hz(),
-            hclk: hclk.hz(),
-            pclk1: pclk1.hz(),
-            pclk2: pclk2.hz(),
-            rtc_clk: rtc_clk,
+            Ok(Clocks::from_tree(&tree, CLK_CFG.rtc_clk, hsi_fallback))
         }
     }
 
@@ -471,12 +604,25 @@ impl CFGR {
             if CLK_CFG.use_hse {
                 // (*RCC::ptr()).ctlr.modify(|_, w| w.bits(RCC_REGS.ctlr & 0x0005_0000));
                 (*RCC::ptr()).ctlr.modify(|_, w| w.hseon().set_bit());
-                while !(*RCC::ptr()).ctlr.read().hserdy().bit_is_set() {}
+                if !wait_ready(|| (*RCC::ptr()).ctlr.read().hserdy().bit_is_set()) {
+                    // Stay on HSI.
+                    hse_failed();
+                    return;
+                }
             }
 
             if CLK_CFG.use_pll {
                 (*RCC::ptr()).ctlr.modify(|_, w| w.pllon().set_bit());
-                while !(*RCC::ptr()).ctlr.read().pllrdy().bit_is_set() {}
+                if !wait_ready(|| (*RCC::ptr()).ctlr.read().pllrdy().bit_is_set()) {
+                    // Stay on HSI.
+                    hse_failed();
+                    return;
+                }
+            }
+
+            // Woke up on HSI. Increase wait states before raising SYSCLK.
+            if CLK_CFG.flash_latency > read_flash_latency() {
+                write_flash_latency(CLK_CFG.flash_latency);
             }
 
             // Restore system clock source
@@ -486,6 +632,10 @@ impl CFGR {
                 Sysclk::Pll => { 0b10 }
             };
             (*RCC::ptr()).cfgr0.modify(|_, w| w.sw().bits(sw_bits));
+            while (*RCC::ptr()).cfgr0.read().sws().bits() != sw_bits {}
+
+            // Decrease wait states after lowering SYSCLK.
+            write_flash_latency(CLK_CFG.flash_latency);
 
             // Restore HSI
             if !CLK_CFG.use_hsi {
@@ -496,6 +646,45 @@ impl CFGR {
 }
 
 impl Clocks {
+    fn from_tree(tree: &ClockTree, rtc_clk: Option<Hertz>, hsi_fallback: bool) -> Self {
+        let clocks = Clocks {
+            sysclk: tree.sysclk,
+            hclk: tree.hclk,
+            pclk1: tree.pclk1,
+            pclk2: tree.pclk2,
+            adcclk: tree.adcclk,
+            pclk1_tim: tree.pclk1_tim,
+            pclk2_tim: tree.pclk2_tim,
+            usbclk: tree.usbclk,
+            rtc_clk: rtc_clk,
+            hsi_fallback,
+        };
+
+        if tree.uses_hsi() {
+            // apply calibrated HSI
+            clocks.rescale_hsi(HSI, unsafe { CLK_CFG.hsi_freq })
+        } else {
+            clocks
+        }
+    }
+
+    // Scale clocks made from HSI.
+    fn rescale_hsi(&self, from: u32, to: u32) -> Self {
+        let scale = |freq: Hertz| (((freq.0 as u64) * (to as u64)) / (from as u64)) as u32;
+        Clocks {
+            sysclk: scale(self.sysclk).hz(),
+            hclk: scale(self.hclk).hz(),
+            pclk1: scale(self.pclk1).hz(),
+            pclk2: scale(self.pclk2).hz(),
+            adcclk: scale(self.adcclk).hz(),
+            pclk1_tim: scale(self.pclk1_tim).hz(),
+            pclk2_tim: scale(self.pclk2_tim).hz(),
+            usbclk: self.usbclk.map(|freq| scale(freq).hz()),
+            rtc_clk: self.rtc_clk,
+            hsi_fallback: self.hsi_fallback,
+        }
+    }
+
     pub fn sysclk(&self) -> Hertz {
         self.sysclk
     }
@@ -512,7 +701,164 @@ impl Clocks {
         self.pclk2
     }
 
+    pub fn adcclk(&self) -> Hertz {
+        self.adcclk
+    }
+
+    // Clock of TIM2, TIM3 and TIM4
+    pub fn pclk1_tim(&self) -> Hertz {
+        self.pclk1_tim
+    }
+
+    // Clock of TIM1
+    pub fn pclk2_tim(&self) -> Hertz {
+        self.pclk2_tim
+    }
+
+    // Some(48MHz) if USBHD is used.
+    pub fn usbclk(&self) -> Option<Hertz> {
+        self.usbclk
+    }
+
     pub fn rtc_clk(&self) -> Option<Hertz> {
         self.rtc_clk
     }
+
+    // True if HSE or PLL failed and SYSCLK fell back to HSI.
+    pub fn hsi_fallback(&self) -> bool {
+        self.hsi_fallback
+    }
+}
+
+// Why the chip restarted. Flags in RSTSCKR and PWR_CSR.
+// Power-on reset also sets the pin reset flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetReason {
+    pub power_on: bool,
+    pub pin: bool,
+    pub software: bool,
+    pub independent_watchdog: bool,
+    pub window_watchdog: bool,
+    pub low_power: bool,
+    // Woke up from Standby mode.
+    pub standby: bool,
+    // Wakeup event by RTC alarm or WKUP pin.
+    pub wakeup: bool,
+}
+
+impl ResetReason {
+    // Decode RSTSCKR and PWR_CSR values.
+    pub fn from_bits(rstsckr: u32, pwr_csr: u32) -> Self {
+        ResetReason {
+            pin: rstsckr & (0b1 << 26) != 0,
+            power_on: rstsckr & (0b1 << 27) != 0,
+            software: rstsckr & (0b1 << 28) != 0,
+            independent_watchdog: rstsckr & (0b1 << 29) != 0,
+            window_watchdog: rstsckr & (0b1 << 30) != 0,
+            low_power: rstsckr & (0b1 << 31) != 0,
+            wakeup: pwr_csr & 0b01 != 0,
+            standby: pwr_csr & 0b10 != 0,
+        }
+    }
+
+    // Read reset flags and clear them for the next reset.
+    pub fn read_and_clear() -> Self {
+        unsafe {
+            // PWR clock may be stopped by freeze() if RTC is not used.
+            let pwr_enabled = PWR::is_enabled();
+            PWR::enable_unchecked();
+
+            let reason = ResetReason::from_bits(
+                (*RCC::ptr()).rstsckr.read().bits(),
+                (*PWR::ptr()).csr.read().bits()
+            );
+
+            // RMVF clears reset flags.
+            (*RCC::ptr()).rstsckr.modify(|r, w| w.bits(r.bits() | (0b1 << 24)));
+            // CSBF and CWUF clear standby and wakeup flags.
+            (*PWR::ptr()).ctlr.modify(|r, w| w.bits(r.bits() | (0b1 << 3) | (0b1 << 2)));
+
+            if !pwr_enabled {
+                PWR::disable_unchecked();
+            }
+
+            reason
+        }
+    }
+
+    // Woke up from Standby mode, not a cold boot.
+    pub fn is_standby_wakeup(&self) -> bool {
+        self.standby
+    }
+
+    // Reset by power-on or power-down.
+    pub fn is_cold_boot(&self) -> bool {
+        self.power_on & !self.standby
+    }
+}
+
+// Clock sources of MCO
+pub struct McoSysclk;
+pub struct McoHsi;
+pub struct McoHse;
+pub struct McoPllDiv2;
+
+pub trait McoSource {
+    // CFGR0.MCO bits
+    fn bits() -> u8;
+}
+
+impl McoSource for McoSysclk {
+    fn bits() -> u8 {
+        0b100
+    }
+}
+
+impl McoSource for McoHsi {
+    fn bits() -> u8 {
+        0b101
+    }
+}
+
+impl McoSource for McoHse {
+    fn bits() -> u8 {
+        0b110
+    }
+}
+
+impl McoSource for McoPllDiv2 {
+    fn bits() -> u8 {
+        0b111
+    }
+}
+
+// Microcontroller clock output on PA8
+pub struct Mco<SRC> {
+    pin: PA8<AltOutput<PushPull>>,
+    _source: PhantomData<SRC>,
+}
+
+impl<SRC> Mco<SRC> where SRC: McoSource {
+    // The selected clock must be running. HSE and PLL are not started here.
+    pub fn new(pin: PA8<AltOutput<PushPull>>, _source: SRC) -> Self {
+        unsafe {
+            (*RCC::ptr()).cfgr0.modify(|_, w| w.mco().bits(SRC::bits()));
+        }
+
+        Mco { pin, _source: PhantomData }
+    }
+
+    // Change the clock source.
+    pub fn into_source<NEW>(self, source: NEW) -> Mco<NEW> where NEW: McoSource {
+        Mco::new(self.release(), source)
+    }
+
+    // Stop clock output and return the pin.
+    pub fn release(self) -> PA8<AltOutput<PushPull>> {
+        unsafe {
+            (*RCC::ptr()).cfgr0.modify(|_, w| w.mco().bits(0b000));
+        }
+
+        self.pin
+    }
 }
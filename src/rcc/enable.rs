@@ -0,0 +1,191 @@
+use ch32v1::ch32v103::{
+    RCC,
+    AFIO,
+    GPIOA,
+    GPIOB,
+    GPIOC,
+    GPIOD,
+    ADC,
+    TIM1,
+    TIM2,
+    TIM3,
+    TIM4,
+    SPI1,
+    SPI2,
+    USART1,
+    USART2,
+    USART3,
+    I2C1,
+    I2C2,
+    WWDG,
+    BKP,
+    PWR,
+    CRC,
+    DMA1,
+    USBHD,
+};
+
+// Buses peripherals are connected to.
+// Only owner of the bus can enable, disable and reset the peripherals on it.
+pub struct AHB {
+    _0: (),
+}
+
+pub struct APB1 {
+    _0: (),
+}
+
+pub struct APB2 {
+    _0: (),
+}
+
+impl AHB {
+    pub(crate) fn new() -> Self {
+        AHB { _0: () }
+    }
+}
+
+impl APB1 {
+    pub(crate) fn new() -> Self {
+        APB1 { _0: () }
+    }
+}
+
+impl APB2 {
+    pub(crate) fn new() -> Self {
+        APB2 { _0: () }
+    }
+}
+
+// Bus the peripheral is connected to.
+pub trait RccBus {
+    type Bus;
+}
+
+// Supply or stop the clock to the peripheral.
+// Drivers take the bus in their constructors and enable and reset the peripheral by these.
+pub trait Enable: RccBus {
+    fn enable(bus: &mut Self::Bus);
+    fn disable(bus: &mut Self::Bus);
+    fn is_enabled() -> bool;
+
+    fn is_disabled() -> bool {
+        !Self::is_enabled()
+    }
+}
+
+// Pulse the peripheral reset.
+pub trait Reset: RccBus {
+    fn reset(bus: &mut Self::Bus);
+}
+
+// Without the bus, only for RCC itself; e.g. PWR and BKP for RTC and reset reason.
+// The bit is changed in a critical section, so this doesn't race with the bus owner.
+pub(crate) trait EnableUnchecked {
+    unsafe fn enable_unchecked();
+    unsafe fn disable_unchecked();
+}
+
+macro_rules! bus_enable {
+    ($PER:ident => ($busX:ident, $enr:ident, $bit:expr)) => {
+        impl RccBus for $PER {
+            type Bus = $busX;
+        }
+
+        impl Enable for $PER {
+            #[inline(always)]
+            fn enable(_bus: &mut Self::Bus) {
+                unsafe { Self::enable_unchecked() }
+            }
+
+            #[inline(always)]
+            fn disable(_bus: &mut Self::Bus) {
+                unsafe { Self::disable_unchecked() }
+            }
+
+            #[inline(always)]
+            fn is_enabled() -> bool {
+                unsafe { (*RCC::ptr()).$enr.read().bits() & (0b1 << $bit) != 0 }
+            }
+        }
+
+        impl EnableUnchecked for $PER {
+            #[inline(always)]
+            unsafe fn enable_unchecked() {
+                riscv::interrupt::free(|| {
+                    (*RCC::ptr()).$enr.modify(|r, w| w.bits(r.bits() | (0b1 << $bit)));
+                });
+            }
+
+            #[inline(always)]
+            unsafe fn disable_unchecked() {
+                riscv::interrupt::free(|| {
+                    (*RCC::ptr()).$enr.modify(|r, w| w.bits(r.bits() & !(0b1 << $bit)));
+                });
+            }
+        }
+    };
+}
+
+macro_rules! bus_reset {
+    ($PER:ident => ($rstr:ident, $bit:expr)) => {
+        impl Reset for $PER {
+            #[inline(always)]
+            fn reset(_bus: &mut Self::Bus) {
+                riscv::interrupt::free(|| unsafe {
+                    (*RCC::ptr()).$rstr.modify(|r, w| w.bits(r.bits() | (0b1 << $bit)));
+                    (*RCC::ptr()).$rstr.modify(|r, w| w.bits(r.bits() & !(0b1 << $bit)));
+                });
+            }
+        }
+    };
+}
+
+macro_rules! bus {
+    ($($PER:ident => ($busX:ident, $bit:expr),)+) => {
+        $(
+            bus!(@ $PER, $busX, $bit);
+        )+
+    };
+    (@ $PER:ident, AHB, $bit:expr) => {
+        // No reset register for AHB peripherals.
+        bus_enable!($PER => (AHB, ahbpcenr, $bit));
+    };
+    (@ $PER:ident, APB1, $bit:expr) => {
+        bus_enable!($PER => (APB1, apb1pcenr, $bit));
+        bus_reset!($PER => (apb1prstr, $bit));
+    };
+    (@ $PER:ident, APB2, $bit:expr) => {
+        bus_enable!($PER => (APB2, apb2pcenr, $bit));
+        bus_reset!($PER => (apb2prstr, $bit));
+    };
+}
+
+// Bit positions are the same in PCENR and PRSTR.
+bus! {
+    DMA1 => (AHB, 0),
+    CRC => (AHB, 6),
+    USBHD => (AHB, 12),
+
+    TIM2 => (APB1, 0),
+    TIM3 => (APB1, 1),
+    TIM4 => (APB1, 2),
+    WWDG => (APB1, 11),
+    SPI2 => (APB1, 14),
+    USART2 => (APB1, 17),
+    USART3 => (APB1, 18),
+    I2C1 => (APB1, 21),
+    I2C2 => (APB1, 22),
+    BKP => (APB1, 27),
+    PWR => (APB1, 28),
+
+    AFIO => (APB2, 0),
+    GPIOA => (APB2, 2),
+    GPIOB => (APB2, 3),
+    GPIOC => (APB2, 4),
+    GPIOD => (APB2, 5),
+    ADC => (APB2, 9),
+    TIM1 => (APB2, 11),
+    SPI1 => (APB2, 12),
+    USART1 => (APB2, 14),
+}
@@ -0,0 +1,692 @@
+use crate::time::*;
+
+pub(super) const HSI: u32 = 8_000_000; // Hz
+const SYSCLK_MAX: u32 = 72_000_000; // Hz
+// APB1 is limited to 36MHz.
+const PCLK1_MAX: u32 = 36_000_000; // Hz
+
+// HPRE bits and dividers
+pub(super) const HPRE_DIVS: [(u8, u32); 9] = [
+    (0b0000, 1),
+    (0b1000, 2),
+    (0b1001, 4),
+    (0b1010, 8),
+    (0b1011, 16),
+    (0b1100, 64),
+    (0b1101, 128),
+    (0b1110, 256),
+    (0b1111, 512),
+];
+
+const ADCCLK_MAX: u32 = 14_000_000; // Hz
+#[cfg(feature = "usbhd")]
+const USBCLK: u32 = 48_000_000; // Hz
+
+// ADCPRE bits and dividers
+pub(super) const ADCPRE_DIVS: [(u8, u32); 4] = [(0b00, 2), (0b01, 4), (0b10, 6), (0b11, 8)];
+
+// Max. SYSCLK for each flash wait state.
+// 0: SYSCLK <= 24MHz, 1: SYSCLK <= 48MHz, 2: SYSCLK <= 72MHz
+const FLASH_LATENCY_FREQS: [u32; 3] = [24_000_000, 48_000_000, 72_000_000];
+
+// PPRE1 and PPRE2 bits and dividers
+pub(super) const PPRE_DIVS: [(u8, u32); 5] = [
+    (0b000, 1),
+    (0b100, 2),
+    (0b101, 4),
+    (0b110, 8),
+    (0b111, 16),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sysclk {
+    Hsi,
+    Hse,
+    Pll,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PllClkSrc {
+    Hsi,
+    // supported in extend_ctr
+    HsiDiv2,
+    Hse,
+    HseDiv2,
+}
+
+const PLL_SOURCES: [PllClkSrc; 4] = [PllClkSrc::Hsi, PllClkSrc::HsiDiv2, PllClkSrc::Hse, PllClkSrc::HseDiv2];
+
+#[derive(Clone, Copy)]
+pub(super) enum Rtc {
+    Lsi,
+    Lse,
+    HseDiv128,
+}
+
+// Reason why the requested clocks can not be made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockError {
+    // HSE frequency is out of the oscillator (4-16MHz) or the bypass (<=25MHz) range.
+    HseFrequency(Hertz),
+    // PLL or RTC clock source is HSE but HSE frequency is not given by use_hse() or hse().
+    HseNotConfigured,
+    // RTC clocked by HSE/128 stops in Stop and Standby, so Delay::stop_ms() and
+    // standby_ms() never wake up. Rejected if the stop or standby feature is enabled.
+    RtcStopsInLowPower,
+    // No clock source and PLL multiplier makes the requested SYSCLK.
+    Sysclk {
+        requested: Hertz,
+        closest: Hertz,
+    },
+    // No HPRE makes the requested HCLK from SYSCLK.
+    Hclk {
+        requested: Hertz,
+        closest: Hertz,
+    },
+    // No PPRE1 makes the requested PCLK1 (<=36MHz) from HCLK.
+    Pclk1 {
+        requested: Hertz,
+        closest: Hertz,
+    },
+    // No PPRE2 makes the requested PCLK2 from HCLK.
+    Pclk2 {
+        requested: Hertz,
+        closest: Hertz,
+    },
+    // No ADCPRE makes the requested ADC clock (<=14MHz) from PCLK2.
+    Adcclk {
+        requested: Hertz,
+        closest: Hertz,
+    },
+}
+
+// Clock configuration
+#[derive(Clone)]
+pub struct CFGR {
+    pub(super) hse_freq: Option<u32>,
+    pub(super) hse_bypass: bool,
+    pub(super) pll_source: Option<PllClkSrc>,
+    pub(super) pll_freq: Option<u32>,
+    pub(super) sysclk_source: Option<Sysclk>,
+    pub(super) sysclk: Option<u32>,
+    pub(super) hclk_freq: Option<u32>,
+    pub(super) pclk1_freq: Option<u32>,
+    pub(super) pclk2_freq: Option<u32>,
+    pub(super) adcclk_freq: Option<u32>,
+    pub(super) lse_freq: Option<u32>,
+    pub(super) lse_bypass: bool,
+    pub(super) rtc_source: Option<Rtc>,
+    pub(super) css: bool,
+}
+
+// Clock tree solved from CFGR. Nothing is written to registers yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockTree {
+    pub sysclk_source: Sysclk,
+    pub pll_source: Option<PllClkSrc>,
+    // 2..=16, valid only if pll_source is set.
+    pub pll_multi: u32,
+    pub hpre_bits: u8,
+    pub ppre1_bits: u8,
+    pub ppre2_bits: u8,
+    pub adcpre_bits: u8,
+    pub sysclk: Hertz,
+    pub hclk: Hertz,
+    pub pclk1: Hertz,
+    pub pclk2: Hertz,
+    pub adcclk: Hertz,
+    // Timers run at twice PCLK if APB is divided.
+    pub pclk1_tim: Hertz,
+    pub pclk2_tim: Hertz,
+    pub usbclk: Option<Hertz>,
+}
+
+impl ClockTree {
+    pub(super) fn uses_hsi(&self) -> bool {
+        (self.sysclk_source == Sysclk::Hsi) |
+            (self.pll_source == Some(PllClkSrc::Hsi)) |
+            (self.pll_source == Some(PllClkSrc::HsiDiv2))
+    }
+
+    pub(super) fn uses_hse(&self) -> bool {
+        (self.sysclk_source == Sysclk::Hse) |
+            (self.pll_source == Some(PllClkSrc::Hse)) |
+            (self.pll_source == Some(PllClkSrc::HseDiv2))
+    }
+}
+
+// Pick a frequency closer to the target.
+fn closer(target: u32, current: Option<u32>, candidate: u32) -> Option<u32> {
+    match current {
+        Some(freq) if freq.abs_diff(target) <= candidate.abs_diff(target) => Some(freq),
+        _ => Some(candidate),
+    }
+}
+
+// Get divider from HPRE or PPRE bits.
+pub(super) fn divider(bits: u8, divs: &[(u8, u32)]) -> u32 {
+    divs.iter()
+        .find(|&&(div_bits, _)| div_bits == bits)
+        .map(|&(_, div)| div)
+        // 0b0xxx or 0b0xx is not divided.
+        .unwrap_or(1)
+}
+
+// Flash wait states required for SYSCLK.
+pub fn flash_latency(sysclk: Hertz) -> u8 {
+    FLASH_LATENCY_FREQS.iter()
+        .position(|&max| sysclk.0 <= max)
+        .unwrap_or(FLASH_LATENCY_FREQS.len() - 1) as u8
+}
+
+// Timer clock from PCLK and PPRE bits.
+pub(super) fn timer_clock(pclk: u32, ppre_bits: u8) -> u32 {
+    // 0b0xx: not divided
+    if ppre_bits & 0b100 == 0 { pclk } else { pclk * 2 }
+}
+
+// USB clock is 48MHz from PLL or HSI x 6 if USBHD is used.
+pub(super) fn usb_clock() -> Option<Hertz> {
+    #[cfg(feature = "usbhd")]
+    return Some(USBCLK.hz());
+    #[cfg(not(feature = "usbhd"))]
+    return None;
+}
+
+// Find a prescaler that makes exactly the requested frequency up to max.
+// If not requested, select the smallest divider up to max.
+// Return the closest frequency up to max if not found.
+fn find_prescaler(input: u32, requested: Option<u32>, divs: &[(u8, u32)], max: u32) -> Result<(u8, u32), u32> {
+    let target = match requested {
+        Some(target) => target,
+        None => {
+            let &(bits, div) = divs.iter()
+                .find(|&&(_, div)| input <= max * div)
+                .unwrap_or(&divs[divs.len() - 1]);
+            return Ok((bits, input / div));
+        }
+    };
+
+    let mut closest = None;
+    for &(bits, div) in divs {
+        let freq = input / div;
+        if freq > max {
+            continue;
+        }
+        if freq == target && freq * div == input {
+            return Ok((bits, freq));
+        }
+        closest = closer(target, closest, freq);
+    }
+    Err(closest.unwrap_or(input / divs[divs.len() - 1].1))
+}
+
+impl CFGR {
+    pub(crate) fn new() -> Self {
+        CFGR {
+            hse_freq: None,
+            hse_bypass: false,
+            pll_source: None,
+            pll_freq: None,
+            sysclk_source: None,
+            sysclk: None,
+            hclk_freq: None,
+            pclk1_freq: None,
+            pclk2_freq: None,
+            adcclk_freq: None,
+            lse_freq: None,
+            lse_bypass: false,
+            rtc_source: None,
+            css: false,
+        }
+    }
+
+    pub fn bypass_hse_oscillator(mut self) -> Self {
+        self.hse_bypass = true;
+        self
+    }
+
+    pub fn use_hsi(mut self) -> Self {
+        self.sysclk_source = Some(Sysclk::Hsi);
+        self
+    }
+
+    pub fn use_hse(mut self, freq: Hertz) -> Self {
+        self.sysclk_source = Some(Sysclk::Hse);
+        self.hse_freq = Some(freq.0);
+        self
+    }
+
+    // Turn on HSE without selecting it as SYSCLK; for PLL or RTC clock source.
+    pub fn hse(mut self, freq: Hertz) -> Self {
+        self.hse_freq = Some(freq.0);
+        self
+    }
+
+    pub fn use_pll(mut self, freq: Hertz, src: PllClkSrc) -> Self {
+        self.sysclk_source = Some(Sysclk::Pll);
+        self.pll_source = Some(src);
+        self.pll_freq = Some(freq.0);
+        self
+    }
+
+    // Request SYSCLK and let the solver select HSI, HSE or PLL with any PLL source.
+    // Give HSE frequency by hse() to include HSE in the candidates.
+    // With use_hsi(), use_hse() or use_pll(), that source must make this frequency.
+    pub fn sysclk(mut self, freq: Hertz) -> Self {
+        self.sysclk = Some(freq.0);
+        self
+    }
+
+    pub fn hclk(mut self, freq: Hertz) -> Self {
+        self.hclk_freq = Some(freq.0);
+        self
+    }
+
+    pub fn pclk1(mut self, freq: Hertz) -> Self {
+        self.pclk1_freq = Some(freq.0);
+        self
+    }
+
+    pub fn pclk2(mut self, freq: Hertz) -> Self {
+        self.pclk2_freq = Some(freq.0);
+        self
+    }
+
+    // ADC clock divided from PCLK2 by 2, 4, 6 or 8. Max. 14MHz.
+    // If not set, the fastest one is selected.
+    pub fn adcclk(mut self, freq: Hertz) -> Self {
+        self.adcclk_freq = Some(freq.0);
+        self
+    }
+
+    pub fn bypass_lse_oscillator(mut self) -> Self {
+        self.lse_bypass = true;
+        self
+    }
+
+    pub fn use_lsi(mut self) -> Self {
+        self.rtc_source = Some(Rtc::Lsi);
+        self
+    }
+
+    pub fn use_lse(mut self, freq: Hertz) -> Self {
+        self.rtc_source = Some(Rtc::Lse);
+        self.lse_freq = Some(freq.0);
+        self
+    }
+
+    // HSE frequency must be given by use_hse() or hse().
+    // RTC stops in Stop and Standby mode since HSE stops.
+    // solve() fails with RtcStopsInLowPower if the stop or standby feature is enabled.
+    pub fn use_hse_div128_for_rtc(mut self) -> Self {
+        self.rtc_source = Some(Rtc::HseDiv128);
+        self
+    }
+
+    // Enable clock security system when HSE is used.
+    // Call css_interrupt() from the NMI handler to handle HSE failure.
+    pub fn enable_css(mut self) -> Self {
+        self.css = true;
+        self
+    }
+
+    fn pll_base_freq(&self, src: PllClkSrc) -> Option<u32> {
+        match src {
+            PllClkSrc::Hsi => Some(HSI),
+            PllClkSrc::HsiDiv2 => Some(HSI / 2),
+            PllClkSrc::Hse => self.hse_freq,
+            PllClkSrc::HseDiv2 => self.hse_freq.map(|freq| freq / 2),
+        }
+    }
+
+    // SYSCLK made by HSI or HSE directly if direct, and by PLL from sources.
+    // (source, PLL source, PLL multiplier, SYSCLK)
+    fn sysclk_candidates<'a>(
+        &'a self,
+        sources: &'a [PllClkSrc],
+        direct: bool
+    ) -> impl Iterator<Item = (Sysclk, Option<PllClkSrc>, u32, u32)> + 'a {
+        let hsi = Some((Sysclk::Hsi, None, 0, HSI)).filter(|_| direct);
+        let hse = self.hse_freq.map(|hse| (Sysclk::Hse, None, 0, hse)).filter(|_| direct);
+        let pll = sources.iter()
+            .filter_map(move |&src| self.pll_base_freq(src).map(|base| (src, base)))
+            .flat_map(|(src, base)| (2..=16).map(move |multi| (Sysclk::Pll, Some(src), multi, base * multi)))
+            .filter(|&(_, _, _, freq)| freq <= SYSCLK_MAX)
+            // USBHD needs 48MHz or 72MHz (divided by 1.5) from PLL.
+            .filter(|&(_, _, _, freq)| !cfg!(feature = "usbhd") | (freq == 48_000_000) | (freq == 72_000_000));
+        hsi.into_iter().chain(hse).chain(pll)
+    }
+
+    // Search SYSCLK sources and PLL multipliers for the target frequency.
+    fn search_sysclk(
+        &self,
+        target: u32,
+        sources: &[PllClkSrc],
+        direct: bool
+    ) -> Result<(Sysclk, Option<PllClkSrc>, u32, u32), ClockError> {
+        let mut closest = None;
+        for candidate in self.sysclk_candidates(sources, direct) {
+            if candidate.3 == target {
+                return Ok(candidate);
+            }
+            closest = closer(target, closest, candidate.3);
+        }
+
+        Err(ClockError::Sysclk {
+            requested: target.hz(),
+            closest: closest.unwrap_or(HSI).hz(),
+        })
+    }
+
+    // Solve the clock tree. This has no side effects and no register access, so
+    // it builds and runs on the host as well (see the tests below).
+    pub fn solve(&self) -> Result<ClockTree, ClockError> {
+        if let Some(hse) = self.hse_freq {
+            // check HSE range
+            let valid = if self.hse_bypass {
+                hse <= (25).mhz().0
+            } else {
+                (hse >= (4).mhz().0) & (hse <= (16).mhz().0)
+            };
+            if !valid {
+                return Err(ClockError::HseFrequency(hse.hz()));
+            }
+        } else if let Some(Rtc::HseDiv128) = self.rtc_source {
+            return Err(ClockError::HseNotConfigured);
+        }
+
+        #[cfg(any(feature = "stop", feature = "standby"))]
+        if let Some(Rtc::HseDiv128) = self.rtc_source {
+            return Err(ClockError::RtcStopsInLowPower);
+        }
+
+        let (sysclk_source, pll_source, pll_multi, sysclk) = match self.sysclk_source {
+            Some(Sysclk::Hsi) => (Sysclk::Hsi, None, 0, HSI),
+            Some(Sysclk::Hse) => (Sysclk::Hse, None, 0, self.hse_freq.unwrap()),
+            Some(Sysclk::Pll) => {
+                // pll_source and pll_freq are set with Pll.
+                let src = self.pll_source.unwrap();
+                if self.pll_base_freq(src).is_none() {
+                    return Err(ClockError::HseNotConfigured);
+                }
+                self.search_sysclk(self.pll_freq.unwrap(), &[src], false)?
+            }
+            None =>
+                match self.sysclk {
+                    Some(target) => self.search_sysclk(target, &PLL_SOURCES, true)?,
+                    // Search SYSCLK which makes the requested bus clocks.
+                    None if self.bus_requested() => {
+                        return self.sysclk_candidates(&PLL_SOURCES, true)
+                            .find_map(|candidate| self.solve_buses(candidate).ok())
+                            // report the error on HSI
+                            .map_or_else(|| self.solve_buses((Sysclk::Hsi, None, 0, HSI)), Ok);
+                    }
+                    // use default HSI
+                    None => (Sysclk::Hsi, None, 0, HSI),
+                }
+        };
+
+        // SYSCLK requested together with a source selected by use_*().
+        if let Some(target) = self.sysclk {
+            if sysclk != target {
+                return Err(ClockError::Sysclk {
+                    requested: target.hz(),
+                    closest: sysclk.hz(),
+                });
+            }
+        }
+
+        self.solve_buses((sysclk_source, pll_source, pll_multi, sysclk))
+    }
+
+    fn bus_requested(&self) -> bool {
+        self.hclk_freq.is_some() | self.pclk1_freq.is_some() | self.pclk2_freq.is_some() | self.adcclk_freq.is_some()
+    }
+
+    // Prescalers from SYSCLK down to ADC clock.
+    fn solve_buses(
+        &self,
+        (sysclk_source, pll_source, pll_multi, sysclk): (Sysclk, Option<PllClkSrc>, u32, u32)
+    ) -> Result<ClockTree, ClockError> {
+        let (hpre_bits, hclk) = find_prescaler(sysclk, self.hclk_freq, &HPRE_DIVS, SYSCLK_MAX).map_err(
+            |closest| ClockError::Hclk {
+                requested: self.hclk_freq.unwrap().hz(),
+                closest: closest.hz(),
+            }
+        )?;
+
+        // APB1, up to 36MHz
+        let (ppre1_bits, pclk1) = find_prescaler(hclk, self.pclk1_freq, &PPRE_DIVS, PCLK1_MAX).map_err(
+            |closest| ClockError::Pclk1 {
+                requested: self.pclk1_freq.unwrap().hz(),
+                closest: closest.hz(),
+            }
+        )?;
+
+        // APB2
+        let (ppre2_bits, pclk2) = find_prescaler(hclk, self.pclk2_freq, &PPRE_DIVS, SYSCLK_MAX).map_err(
+            |closest| ClockError::Pclk2 {
+                requested: self.pclk2_freq.unwrap().hz(),
+                closest: closest.hz(),
+            }
+        )?;
+
+        // ADC
+        let (adcpre_bits, adcclk) = find_prescaler(pclk2, self.adcclk_freq, &ADCPRE_DIVS, ADCCLK_MAX).map_err(
+            |closest| ClockError::Adcclk {
+                requested: self.adcclk_freq.unwrap().hz(),
+                closest: closest.hz(),
+            }
+        )?;
+
+        Ok(ClockTree {
+            sysclk_source,
+            pll_source,
+            pll_multi,
+            hpre_bits,
+            ppre1_bits,
+            ppre2_bits,
+            adcpre_bits,
+            sysclk: sysclk.hz(),
+            hclk: hclk.hz(),
+            pclk1: pclk1.hz(),
+            pclk2: pclk2.hz(),
+            adcclk: adcclk.hz(),
+            pclk1_tim: timer_clock(pclk1, ppre1_bits).hz(),
+            pclk2_tim: timer_clock(pclk2, ppre2_bits).hz(),
+            usbclk: usb_clock(),
+        })
+    }
+
+    // Clock tree on HSI used when HSE or PLL failed to start.
+    pub(super) fn fallback_tree(&self) -> ClockTree {
+        // Not requested prescalers never fail.
+        let (hpre_bits, hclk) = find_prescaler(HSI, self.hclk_freq, &HPRE_DIVS, SYSCLK_MAX).unwrap_or(
+            find_prescaler(HSI, None, &HPRE_DIVS, SYSCLK_MAX).unwrap()
+        );
+        let (ppre1_bits, pclk1) = find_prescaler(hclk, self.pclk1_freq, &PPRE_DIVS, PCLK1_MAX).unwrap_or(
+            find_prescaler(hclk, None, &PPRE_DIVS, PCLK1_MAX).unwrap()
+        );
+        let (ppre2_bits, pclk2) = find_prescaler(hclk, self.pclk2_freq, &PPRE_DIVS, SYSCLK_MAX).unwrap_or(
+            find_prescaler(hclk, None, &PPRE_DIVS, SYSCLK_MAX).unwrap()
+        );
+        let (adcpre_bits, adcclk) = find_prescaler(pclk2, self.adcclk_freq, &ADCPRE_DIVS, ADCCLK_MAX).unwrap_or(
+            find_prescaler(pclk2, None, &ADCPRE_DIVS, ADCCLK_MAX).unwrap()
+        );
+
+        ClockTree {
+            sysclk_source: Sysclk::Hsi,
+            pll_source: None,
+            pll_multi: 0,
+            hpre_bits,
+            ppre1_bits,
+            ppre2_bits,
+            adcpre_bits,
+            sysclk: HSI.hz(),
+            hclk: hclk.hz(),
+            pclk1: pclk1.hz(),
+            pclk2: pclk2.hz(),
+            adcclk: adcclk.hz(),
+            pclk1_tim: timer_clock(pclk1, ppre1_bits).hz(),
+            pclk2_tim: timer_clock(pclk2, ppre2_bits).hz(),
+            usbclk: usb_clock(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_hsi() {
+        let tree = CFGR::new().solve().unwrap();
+        assert_eq!(tree.sysclk_source, Sysclk::Hsi);
+        assert_eq!(tree.sysclk, HSI.hz());
+        assert_eq!(tree.hclk, HSI.hz());
+        assert_eq!(tree.pclk1, HSI.hz());
+        assert_eq!(tree.pclk2, HSI.hz());
+        // HSI / 2
+        assert_eq!(tree.adcclk, (4).mhz());
+    }
+
+    #[test]
+    // USBHD limits PLL to 48MHz and 72MHz.
+    #[cfg(not(feature = "usbhd"))]
+    fn pll_exact_on_each_source() {
+        let tree = CFGR::new().use_pll((48).mhz(), PllClkSrc::Hsi).solve().unwrap();
+        assert_eq!((tree.pll_source, tree.pll_multi, tree.sysclk), (Some(PllClkSrc::Hsi), 6, (48).mhz()));
+
+        let tree = CFGR::new().use_pll((48).mhz(), PllClkSrc::HsiDiv2).solve().unwrap();
+        assert_eq!((tree.pll_source, tree.pll_multi, tree.sysclk), (Some(PllClkSrc::HsiDiv2), 12, (48).mhz()));
+
+        let tree = CFGR::new().hse((8).mhz()).use_pll((72).mhz(), PllClkSrc::Hse).solve().unwrap();
+        assert_eq!((tree.pll_source, tree.pll_multi, tree.sysclk), (Some(PllClkSrc::Hse), 9, (72).mhz()));
+
+        let tree = CFGR::new().hse((12).mhz()).use_pll((66).mhz(), PllClkSrc::HseDiv2).solve().unwrap();
+        assert_eq!((tree.pll_source, tree.pll_multi, tree.sysclk), (Some(PllClkSrc::HseDiv2), 11, (66).mhz()));
+    }
+
+    #[test]
+    // USBHD limits PLL to 48MHz and 72MHz.
+    #[cfg(not(feature = "usbhd"))]
+    fn sysclk_searches_sources() {
+        let tree = CFGR::new().use_hse((12).mhz()).sysclk((12).mhz()).solve().unwrap();
+        assert_eq!(tree.sysclk_source, Sysclk::Hse);
+
+        let tree = CFGR::new().sysclk((20).mhz()).solve().unwrap();
+        assert_eq!((tree.pll_source, tree.pll_multi), (Some(PllClkSrc::HsiDiv2), 5));
+    }
+
+    #[test]
+    fn sysclk_miss_reports_closest() {
+        assert_eq!(
+            CFGR::new().use_pll((50).mhz(), PllClkSrc::Hsi).solve(),
+            Err(ClockError::Sysclk { requested: (50).mhz(), closest: (48).mhz() })
+        );
+    }
+
+    #[test]
+    fn hclk_miss_reports_closest() {
+        assert_eq!(
+            CFGR::new().use_pll((48).mhz(), PllClkSrc::Hsi).hclk((8).mhz()).solve(),
+            Err(ClockError::Hclk { requested: (8).mhz(), closest: (6).mhz() })
+        );
+    }
+
+    #[test]
+    fn pclk1_up_to_36mhz() {
+        let tree = CFGR::new().use_pll((72).mhz(), PllClkSrc::Hsi).solve().unwrap();
+        assert_eq!((tree.pclk1, tree.ppre1_bits), ((36).mhz(), 0b100));
+        assert_eq!(tree.pclk1_tim, (72).mhz());
+        assert_eq!(tree.pclk2, (72).mhz());
+        // 72MHz / 6
+        assert_eq!(tree.adcclk, (12).mhz());
+
+        assert_eq!(
+            CFGR::new().use_pll((72).mhz(), PllClkSrc::Hsi).pclk1((72).mhz()).solve(),
+            Err(ClockError::Pclk1 { requested: (72).mhz(), closest: (36).mhz() })
+        );
+    }
+
+    #[test]
+    fn sysclk_with_source() {
+        // Same result whichever is called first.
+        let conflict = Err(ClockError::Sysclk { requested: (72).mhz(), closest: (8).mhz() });
+        assert_eq!(CFGR::new().sysclk((72).mhz()).use_hse((8).mhz()).solve(), conflict);
+        assert_eq!(CFGR::new().use_hse((8).mhz()).sysclk((72).mhz()).solve(), conflict);
+        assert_eq!(
+            CFGR::new().sysclk((72).mhz()).use_pll((48).mhz(), PllClkSrc::Hsi).solve(),
+            Err(ClockError::Sysclk { requested: (72).mhz(), closest: (48).mhz() })
+        );
+
+        let tree = CFGR::new().sysclk((48).mhz()).use_pll((48).mhz(), PllClkSrc::Hsi).solve().unwrap();
+        assert_eq!(tree.sysclk, (48).mhz());
+
+        // hse() only adds HSE to the candidates.
+        let tree = CFGR::new().sysclk((12).mhz()).hse((12).mhz()).solve().unwrap();
+        assert_eq!(tree.sysclk_source, Sysclk::Hse);
+    }
+
+    #[test]
+    fn bus_clocks_search_sysclk() {
+        let tree = CFGR::new().hclk((24).mhz()).solve().unwrap();
+        assert_eq!(tree.hclk, (24).mhz());
+
+        let tree = CFGR::new().pclk1((36).mhz()).pclk2((72).mhz()).solve().unwrap();
+        assert_eq!((tree.sysclk, tree.pclk1, tree.pclk2), ((72).mhz(), (36).mhz(), (72).mhz()));
+
+        let tree = CFGR::new().hse((12).mhz()).pclk2((6).mhz()).solve().unwrap();
+        assert_eq!((tree.sysclk_source, tree.pclk2), (Sysclk::Hse, (6).mhz()));
+
+        // nothing makes 19MHz, closest from HSI
+        assert_eq!(
+            CFGR::new().hclk((19).mhz()).solve(),
+            Err(ClockError::Hclk { requested: (19).mhz(), closest: (8).mhz() })
+        );
+    }
+
+    #[test]
+    fn flash_latency_boundaries() {
+        assert_eq!(flash_latency((8).mhz()), 0);
+        assert_eq!(flash_latency((24).mhz()), 0);
+        assert_eq!(flash_latency(Hertz(24_000_001)), 1);
+        assert_eq!(flash_latency((48).mhz()), 1);
+        assert_eq!(flash_latency(Hertz(48_000_001)), 2);
+        assert_eq!(flash_latency((72).mhz()), 2);
+    }
+
+    #[test]
+    fn hse_not_configured() {
+        assert_eq!(
+            CFGR::new().use_pll((48).mhz(), PllClkSrc::Hse).solve(),
+            Err(ClockError::HseNotConfigured)
+        );
+        assert_eq!(
+            CFGR::new().use_hse_div128_for_rtc().solve(),
+            Err(ClockError::HseNotConfigured)
+        );
+    }
+
+    #[test]
+    fn rtc_on_hse_div128() {
+        let cfgr = CFGR::new().hse((8).mhz()).use_hse_div128_for_rtc();
+        if cfg!(any(feature = "stop", feature = "standby")) {
+            assert_eq!(cfgr.solve(), Err(ClockError::RtcStopsInLowPower));
+        } else {
+            assert!(cfgr.solve().is_ok());
+        }
+    }
+
+    #[test]
+    fn hse_range() {
+        assert_eq!(CFGR::new().use_hse((3).mhz()).solve(), Err(ClockError::HseFrequency((3).mhz())));
+        assert_eq!(CFGR::new().use_hse((20).mhz()).solve(), Err(ClockError::HseFrequency((20).mhz())));
+        assert!(CFGR::new().use_hse((20).mhz()).bypass_hse_oscillator().solve().is_ok());
+        assert_eq!(
+            CFGR::new().use_hse((26).mhz()).bypass_hse_oscillator().solve(),
+            Err(ClockError::HseFrequency((26).mhz()))
+        );
+    }
+}
\ No newline at end of file
@@ -4,15 +4,23 @@ use nb;
 use core::convert::Infallible;
 use core::fmt;
 
-use ch32v1::ch32v103::{ AFIO, RCC, USART1 };
+use ch32v1::ch32v103::{ USART1, USART2, USART3 };
 use crate::time::*;
 use crate::rcc::*;
 use crate::gpio::*;
-use crate::gpio::gpioa::{ PA9, PA10 };
-use crate::gpio::gpiob::{ PB6, PB7 };
+use crate::afio::{ Afio, Pins, NoRemap, Remap, RemapMode };
+use crate::gpio::gpioa::{ PA2, PA3, PA9, PA10 };
+use crate::gpio::gpiob::{ PB6, PB7, PB10, PB11 };
+#[cfg(feature = "r8t6")]
+use crate::afio::PartialRemap;
+#[cfg(feature = "r8t6")]
+use crate::gpio::gpioc::{ PC10, PC11 };
+
+mod baud;
+pub use baud::*;
 
 // define serial error
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum UsartError {
     // Framing error
     Framing,
@@ -24,184 +32,402 @@ pub enum UsartError {
     Parity,
 }
 
-// define Tx and Rx Pin trait
-// Serial receiver
-pub struct Rx<USART> {
-    _usart: PhantomData<USART>,
+// Number of errors found by Rx::read()
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    pub framing: u32,
+    pub noise: u32,
+    pub overrun: u32,
+    pub parity: u32,
 }
 
-// Serial transmitter
-pub struct Tx<USART> {
-    _usart: PhantomData<USART>,
+// Word length including parity bit.
+// 8N1 and 7E1 are 8 bits, 8E1 is 9 bits.
+// 9N1 is not supported by Read<u8> and Write<u8>, the 9th bit is dropped on read and sent as 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WordLength {
+    DataBits8,
+    DataBits9,
 }
 
-pub trait TxPin<USART> {
-    fn remap(&self) -> bool;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    ParityNone,
+    ParityEven,
+    ParityOdd,
 }
-pub trait RxPin<USART> {
-    fn remap(&self) -> bool;
+
+// STOP bits in CTLR2
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    STOP1 = 0b00,
+    STOP0P5 = 0b01,
+    STOP2 = 0b10,
+    STOP1P5 = 0b11,
 }
 
-impl TxPin<USART1> for PA9<AltOutput<PushPull>> {
-    fn remap(&self) -> bool {
-        false
-    }
+// Serial frame configuration
+// Config::default() is 115200bps 8N1. Bps can be used as Config of 8N1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub baudrate: Bps,
+    pub wordlength: WordLength,
+    pub parity: Parity,
+    pub stopbits: StopBits,
+    // Allowed baud rate error in 0.1%
+    pub tolerance_permille: u32,
 }
-impl RxPin<USART1> for PA10<Input<Floating>> {
-    fn remap(&self) -> bool {
-        false
+
+impl Config {
+    pub fn baudrate(mut self, baudrate: Bps) -> Self {
+        self.baudrate = baudrate;
+        self
     }
-}
-impl RxPin<USART1> for PA10<Input<PullUp>> {
-    fn remap(&self) -> bool {
-        false
+
+    pub fn wordlength_8(mut self) -> Self {
+        self.wordlength = WordLength::DataBits8;
+        self
     }
-}
 
-// Remap
-impl TxPin<USART1> for PB6<AltOutput<PushPull>> {
-    fn remap(&self) -> bool {
-        true
+    pub fn wordlength_9(mut self) -> Self {
+        self.wordlength = WordLength::DataBits9;
+        self
+    }
+
+    pub fn parity_none(mut self) -> Self {
+        self.parity = Parity::ParityNone;
+        self
+    }
+
+    pub fn parity_even(mut self) -> Self {
+        self.parity = Parity::ParityEven;
+        self
+    }
+
+    pub fn parity_odd(mut self) -> Self {
+        self.parity = Parity::ParityOdd;
+        self
+    }
+
+    pub fn stopbits(mut self, stopbits: StopBits) -> Self {
+        self.stopbits = stopbits;
+        self
+    }
+
+    // 20 is 2%
+    pub fn tolerance_permille(mut self, tolerance: u32) -> Self {
+        self.tolerance_permille = tolerance;
+        self
     }
 }
-impl RxPin<USART1> for PB7<Input<Floating>> {
-    fn remap(&self) -> bool {
-        true
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baudrate: Bps(115_200),
+            wordlength: WordLength::DataBits8,
+            parity: Parity::ParityNone,
+            stopbits: StopBits::STOP1,
+            tolerance_permille: 20,
+        }
     }
 }
-impl RxPin<USART1> for PB7<Input<PullUp>> {
-    fn remap(&self) -> bool {
-        true
+
+impl From<Bps> for Config {
+    fn from(baudrate: Bps) -> Self {
+        Config::default().baudrate(baudrate)
     }
 }
 
+// define Tx and Rx Pin trait
+// Serial receiver
+pub struct Rx<USART> {
+    _usart: PhantomData<USART>,
+    // Valid byte left in DATAR on overrun
+    pending: Option<u8>,
+    errors: ErrorCounts,
+    // 0x7f to drop the parity bit of 7E1 and 7O1
+    data_mask: u8,
+}
+
+// Serial transmitter
+pub struct Tx<USART> {
+    _usart: PhantomData<USART>,
+    baud_rate: Bps,
+    tolerance: u32,
+}
+
+// (TX, RX)
+impl Pins<USART1, NoRemap> for (PA9<AltOutput<PushPull>>, PA10<Input<Floating>>) {}
+impl Pins<USART1, NoRemap> for (PA9<AltOutput<PushPull>>, PA10<Input<PullUp>>) {}
+
+// Remap
+impl Pins<USART1, Remap> for (PB6<AltOutput<PushPull>>, PB7<Input<Floating>>) {}
+impl Pins<USART1, Remap> for (PB6<AltOutput<PushPull>>, PB7<Input<PullUp>>) {}
+
+// Remapped pins of USART2 (PD5, PD6) are not on the packages.
+impl Pins<USART2, NoRemap> for (PA2<AltOutput<PushPull>>, PA3<Input<Floating>>) {}
+impl Pins<USART2, NoRemap> for (PA2<AltOutput<PushPull>>, PA3<Input<PullUp>>) {}
+
+// Full remapped pins of USART3 (PD8, PD9) are not on the packages.
+impl Pins<USART3, NoRemap> for (PB10<AltOutput<PushPull>>, PB11<Input<Floating>>) {}
+impl Pins<USART3, NoRemap> for (PB10<AltOutput<PushPull>>, PB11<Input<PullUp>>) {}
+#[cfg(feature = "r8t6")]
+impl Pins<USART3, PartialRemap> for (PC10<AltOutput<PushPull>>, PC11<Input<Floating>>) {}
+#[cfg(feature = "r8t6")]
+impl Pins<USART3, PartialRemap> for (PC10<AltOutput<PushPull>>, PC11<Input<PullUp>>) {}
+
 // Serial abstraction
 pub struct Serial<USART, PINS> {
     usart: USART,
     pins: PINS,
     base_freq: Hertz,
+    config: Config,
+    actual_baud: Bps,
 }
 
-impl<TX, RX> Serial<USART1, (TX, RX)> {
-    // init USART
-    pub fn usart1(usart: USART1, pins: (TX, RX), baud_rate: Bps, clocks: &Clocks) -> Self
-        where TX: TxPin<USART1>, RX: RxPin<USART1>
-    {
-        // enable USART
-        unsafe {
-            // ToDo: Want to check while compiling.
-            // remap USART1
-            if pins.0.remap() & pins.1.remap() {
-                // clock is required before remap.
-                (*RCC::ptr()).apb2pcenr.modify(|_, w| w.afioen().set_bit());
-                (*AFIO::ptr()).pcfr.modify(|_, w| w.usart1rm().set_bit());
-            } else if pins.0.remap() | pins.1.remap() {
-                unreachable!();
-            }
-
-            // provide clock to USART1
-            (*RCC::ptr()).apb2pcenr.modify(|_, w| w.usart1en().set_bit());
-
-            // USARTDIV = Fclk / bps / 16
-            // USARTDIV * 16 = Fclk / bps
-            // BRR = USARTDIV_M << 4 + USARTDIV_F = USARTDIV
-            // 8 MHz / 9600 / 16 = 52.08
-            // 8 MHz / 115200 / 16 = 4.34
-            let brr_div: u32 = clocks.pclk2().0 / baud_rate.0;
-            (*USART1::ptr()).brr.write(|w| w.bits(brr_div));
-
-            // disable harware flow control
-            (*USART1::ptr()).ctlr3.modify(|_, w| w.ctse().clear_bit().rtse().clear_bit());
-            // enable USART, enable transmitter and receiver
-            (*USART1::ptr()).ctlr1.modify(|_, w| w.ue().set_bit().te().set_bit().re().set_bit());
-        }
+// USART1 is on APB2, USART2 and USART3 are on APB1.
+macro_rules! serial {
+    ($USARTX:ident, $usartx:ident, $pclkx:ident) => {
+        impl<PINS> Serial<$USARTX, PINS> {
+            // init USART
+            // Remap is selected by the pins.
+            pub fn $usartx<REMAP>(
+                usart: $USARTX,
+                pins: PINS,
+                afio: &mut Afio,
+                bus: &mut <$USARTX as RccBus>::Bus,
+                config: impl Into<Config>,
+                clocks: &Clocks
+            ) -> Result<Self, ConfigError>
+                where PINS: Pins<$USARTX, REMAP>, REMAP: RemapMode
+            {
+                let config = config.into();
+                let (brr, actual_baud) = checked_brr_value(
+                    clocks.$pclkx(),
+                    config.baudrate,
+                    config.tolerance_permille
+                )?;
 
-        Serial { usart: usart, pins: pins, base_freq: clocks.pclk2() }
-    }
+                afio.remap::<$USARTX, REMAP>();
+                // provide clock to USART
+                $USARTX::enable(bus);
+                $USARTX::reset(bus);
 
-    /// Splits the `Serial` abstraction into a transmitter and a receiver half
-    pub fn split(self) -> (Tx<USART1>, Rx<USART1>) {
-        (
-            Tx {
-                _usart: PhantomData,
-            },
-            Rx {
-                _usart: PhantomData,
-            },
-        )
-    }
+                // enable USART
+                unsafe {
+                    Tx::<$USARTX>::write_brr(brr);
 
-    pub fn get_base_freq(&self) -> Hertz {
-        self.base_freq
-    }
-}
+                    // frame format
+                    let (pce, ps) = match config.parity {
+                        Parity::ParityNone => (false, false),
+                        Parity::ParityEven => (true, false),
+                        Parity::ParityOdd => (true, true),
+                    };
+                    (*$USARTX::ptr()).ctlr1.modify(|_, w|
+                        w
+                            .m()
+                            .bit(config.wordlength == WordLength::DataBits9)
+                            .pce()
+                            .bit(pce)
+                            .ps()
+                            .bit(ps)
+                    );
+                    (*$USARTX::ptr()).ctlr2.modify(|_, w| w.stop().bits(config.stopbits as u8));
+
+                    // disable harware flow control
+                    (*$USARTX::ptr()).ctlr3.modify(|_, w| w.ctse().clear_bit().rtse().clear_bit());
+                    // enable USART, enable transmitter and receiver
+                    (*$USARTX::ptr()).ctlr1.modify(|_, w| w.ue().set_bit().te().set_bit().re().set_bit());
+                }
 
-impl serial::Write<u8> for Tx<USART1> {
-    type Error = Infallible;
+                Ok(Serial {
+                    usart: usart,
+                    pins: pins,
+                    base_freq: clocks.$pclkx(),
+                    config: config,
+                    actual_baud: actual_baud,
+                })
+            }
 
-    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
-        unsafe {
-            // check TX register is empty
-            if (*USART1::ptr()).statr.read().txe().bit_is_set() {
-                (*USART1::ptr()).datar.write(|w| w.bits(byte as u32));
+            // Recompute BRR after CFGR::reconfigure().
+            // BRR is not changed if the baud rate is out of the tolerance.
+            pub fn reconfigure(&mut self, clocks: &Clocks) -> Result<(), ConfigError> {
+                let (brr, actual_baud) = checked_brr_value(
+                    clocks.$pclkx(),
+                    self.config.baudrate,
+                    self.config.tolerance_permille
+                )?;
+                Tx::<$USARTX>::write_brr(brr);
+                self.base_freq = clocks.$pclkx();
+                self.actual_baud = actual_baud;
                 Ok(())
-            } else {
-                Err(nb::Error::WouldBlock)
+            }
+
+            pub fn actual_baud(&self) -> Bps {
+                self.actual_baud
+            }
+
+            /// Splits the `Serial` abstraction into a transmitter and a receiver half
+            pub fn split(self) -> (Tx<$USARTX>, Rx<$USARTX>) {
+                (
+                    Tx {
+                        _usart: PhantomData,
+                        baud_rate: self.config.baudrate,
+                        tolerance: self.config.tolerance_permille,
+                    },
+                    Rx {
+                        _usart: PhantomData,
+                        pending: None,
+                        errors: ErrorCounts::default(),
+                        data_mask: match (self.config.wordlength, self.config.parity) {
+                            (WordLength::DataBits8, Parity::ParityEven | Parity::ParityOdd) => 0x7f,
+                            _ => 0xff,
+                        },
+                    },
+                )
+            }
+
+            pub fn get_base_freq(&self) -> Hertz {
+                self.base_freq
             }
         }
-    }
 
-    fn flush(&mut self) -> nb::Result<(), Self::Error> {
-        unsafe {
-            if (*USART1::ptr()).statr.read().tc().bit_is_set() {
-                Ok(())
-            } else {
-                Err(nb::Error::WouldBlock)
+        impl serial::Write<u8> for Tx<$USARTX> {
+            type Error = Infallible;
+
+            fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+                unsafe {
+                    // check TX register is empty
+                    if (*$USARTX::ptr()).statr.read().txe().bit_is_set() {
+                        (*$USARTX::ptr()).datar.write(|w| w.bits(byte as u32));
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+            }
+
+            fn flush(&mut self) -> nb::Result<(), Self::Error> {
+                unsafe {
+                    if (*$USARTX::ptr()).statr.read().tc().bit_is_set() {
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
             }
         }
-    }
-}
 
-impl Tx<USART1> {
-    pub fn is_ready(&self) -> bool {
-        unsafe { (*USART1::ptr()).statr.read().txe().bit_is_set() }
-    }
-}
+        impl Tx<$USARTX> {
+            fn write_brr(brr: u32) {
+                unsafe {
+                    // wait until the last byte is sent.
+                    while (*$USARTX::ptr()).statr.read().tc().bit_is_clear() {}
+                    (*$USARTX::ptr()).brr.write(|w| w.bits(brr));
+                }
+            }
 
-impl serial::Read<u8> for Rx<USART1> {
-    type Error = UsartError;
+            pub fn is_ready(&self) -> bool {
+                unsafe { (*$USARTX::ptr()).statr.read().txe().bit_is_set() }
+            }
+
+            // Recompute BRR after CFGR::reconfigure(). Rx shares the same BRR.
+            pub fn reconfigure(&mut self, clocks: &Clocks) -> Result<Bps, ConfigError> {
+                let (brr, actual_baud) = checked_brr_value(clocks.$pclkx(), self.baud_rate, self.tolerance)?;
+                Self::write_brr(brr);
+                Ok(actual_baud)
+            }
+        }
+
+        impl serial::Read<u8> for Rx<$USARTX> {
+            type Error = UsartError;
+
+            // Error flags are cleared by reading STATR then DATAR.
+            // On overrun, the byte in DATAR is returned by the next read().
+            // With parity in 8 bit words, the parity bit in MSB is masked out.
+            fn read(&mut self) -> nb::Result<u8, UsartError> {
+                if let Some(byte) = self.pending.take() {
+                    return Ok(byte);
+                }
 
-    fn read(&mut self) -> nb::Result<u8, UsartError> {
-        unsafe {
-            // read STATR
-            let statr = (*USART1::ptr()).statr.read();
-            if statr.rxne().bit_is_set() {
-                Ok((*USART1::ptr()).datar.read().bits() as u8)
-            } else {
-                Err(
-                    if statr.ore().bit_is_set() {
-                        nb::Error::Other(UsartError::Overrun)
+                unsafe {
+                    // read STATR
+                    let statr = (*$USARTX::ptr()).statr.read();
+                    let error = if statr.ore().bit_is_set() {
+                        Some(UsartError::Overrun)
                     } else if statr.ne().bit_is_set() {
-                        nb::Error::Other(UsartError::Noise)
+                        Some(UsartError::Noise)
                     } else if statr.fe().bit_is_set() {
-                        nb::Error::Other(UsartError::Framing)
+                        Some(UsartError::Framing)
                     } else if statr.pe().bit_is_set() {
-                        nb::Error::Other(UsartError::Parity)
+                        Some(UsartError::Parity)
                     } else {
-                        nb::Error::WouldBlock
+                        None
+                    };
+
+                    match error {
+                        Some(error) => {
+                            // clear error flags
+                            let byte = (*$USARTX::ptr()).datar.read().bits() as u8 & self.data_mask;
+                            let corrupted = statr.ne().bit_is_set() | statr.fe().bit_is_set() | statr.pe().bit_is_set();
+                            if (error == UsartError::Overrun) & !corrupted {
+                                self.pending = Some(byte);
+                            }
+                            self.count_error(error);
+                            Err(nb::Error::Other(error))
+                        }
+                        None if statr.rxne().bit_is_set() => {
+                            Ok((*$USARTX::ptr()).datar.read().bits() as u8 & self.data_mask)
+                        }
+                        None => Err(nb::Error::WouldBlock),
                     }
-                )
+                }
             }
         }
-    }
-}
 
-impl Rx<USART1> {
-    pub fn is_empty(&self) -> bool {
-        unsafe { (*USART1::ptr()).statr.read().rxne().bit_is_clear() }
-    }
+        impl Rx<$USARTX> {
+            pub fn is_empty(&self) -> bool {
+                self.pending.is_none() && unsafe { (*$USARTX::ptr()).statr.read().rxne().bit_is_clear() }
+            }
+
+            // Discard received data and clear error flags.
+            pub fn clear_errors(&mut self) {
+                self.pending = None;
+                unsafe {
+                    (*$USARTX::ptr()).statr.read();
+                    (*$USARTX::ptr()).datar.read();
+                }
+            }
+
+            fn count_error(&mut self, error: UsartError) {
+                let count = match error {
+                    UsartError::Framing => &mut self.errors.framing,
+                    UsartError::Noise => &mut self.errors.noise,
+                    UsartError::Overrun => &mut self.errors.overrun,
+                    UsartError::Parity => &mut self.errors.parity,
+                };
+                *count = count.wrapping_add(1);
+            }
+
+            pub fn error_counts(&self) -> ErrorCounts {
+                self.errors
+            }
+
+            pub fn reset_error_counts(&mut self) {
+                self.errors = ErrorCounts::default();
+            }
+        }
+
+        // Only implimenting this marker trait, methods in blocking::serial::Write are available.
+        impl blocking::serial::write::Default<u8> for Tx<$USARTX> {}
+    };
 }
 
+serial!(USART1, usart1, pclk2);
+serial!(USART2, usart2, pclk1);
+serial!(USART3, usart3, pclk1);
+
 pub struct SerialWriter<T> where T: serial::Write<u8> {
     serial: T,
 }
@@ -224,9 +450,6 @@ impl<T> fmt::Write for SerialWriter<T> where T: serial::Write<u8> {
     }
 }
 
-// Only implimenting this marker trait, methods in blocking::serial::Write are available.
-impl blocking::serial::write::Default<u8> for Tx<USART1> {}
-
 // impl blocking::serial::Write<u8> for Tx<USART1> {
 //     type Error = Infallible;
 
@@ -0,0 +1,93 @@
+use crate::time::*;
+
+// Serial can not be configured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    // Actual baud rate deviates from the requested one over the tolerance.
+    BaudRate {
+        requested: Bps,
+        actual: Bps,
+    },
+    // Baud rate of 0 was requested.
+    ZeroBaudRate,
+}
+
+// USARTDIV = Fclk / bps / 16
+// USARTDIV * 16 = Fclk / bps
+// BRR = USARTDIV_M << 4 + USARTDIV_F = USARTDIV * 16
+// 8 MHz / 9600 / 16 = 52.08
+// 8 MHz / 115200 / 16 = 4.34
+
+// BRR rounded to the nearest and the actual baud rate.
+// No register access. baud_rate must not be 0, use checked_brr_value to get an error instead.
+pub fn brr_value(pclk: Hertz, baud_rate: Bps) -> (u32, Bps) {
+    let brr = (pclk.0 + baud_rate.0 / 2) / baud_rate.0;
+    // USARTDIV must be 1 or more and fit in 16 bits.
+    let brr = brr.max(16).min(0xffff);
+    (brr, Bps((pclk.0 + brr / 2) / brr))
+}
+
+// Deviation of the actual baud rate in 0.1%
+pub fn baud_error_permille(requested: Bps, actual: Bps) -> u32 {
+    let diff = if actual.0 > requested.0 { actual.0 - requested.0 } else { requested.0 - actual.0 };
+    ((diff as u64 * 1000 + requested.0 as u64 / 2) / requested.0 as u64) as u32
+}
+
+// BRR within the tolerance
+pub fn checked_brr_value(pclk: Hertz, baud_rate: Bps, tolerance_permille: u32) -> Result<(u32, Bps), ConfigError> {
+    if baud_rate.0 == 0 {
+        return Err(ConfigError::ZeroBaudRate);
+    }
+    let (brr, actual) = brr_value(pclk, baud_rate);
+    if baud_error_permille(baud_rate, actual) > tolerance_permille {
+        return Err(ConfigError::BaudRate { requested: baud_rate, actual });
+    }
+    Ok((brr, actual))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brr_rounds_to_nearest() {
+        // 8MHz / 115200 = 69.44
+        assert_eq!(brr_value((8).mhz(), (115_200).bps()), (69, Bps(115_942)));
+        // 8MHz / 9600 = 833.33
+        assert_eq!(brr_value((8).mhz(), (9_600).bps()), (833, Bps(9_604)));
+        // 72MHz / 115200 = 625
+        assert_eq!(brr_value((72).mhz(), (115_200).bps()), (625, Bps(115_200)));
+    }
+
+    #[test]
+    fn brr_is_clamped() {
+        assert_eq!(brr_value((8).mhz(), (1_000_000).bps()).0, 16);
+        assert_eq!(brr_value((72).mhz(), (300).bps()).0, 0xffff);
+    }
+
+    #[test]
+    fn baud_error() {
+        assert_eq!(baud_error_permille((115_200).bps(), Bps(115_942)), 6);
+        assert_eq!(baud_error_permille((115_200).bps(), Bps(115_200)), 0);
+        assert_eq!(baud_error_permille((1_000).bps(), Bps(950)), 50);
+    }
+
+    #[test]
+    fn checked_brr_tolerance() {
+        assert_eq!(checked_brr_value((8).mhz(), (115_200).bps(), 20), Ok((69, Bps(115_942))));
+        assert_eq!(
+            checked_brr_value((8).mhz(), (115_200).bps(), 5),
+            Err(ConfigError::BaudRate { requested: (115_200).bps(), actual: Bps(115_942) })
+        );
+        // BRR clamped to 16 gives 500000 instead of 1000000
+        assert_eq!(
+            checked_brr_value((8).mhz(), (1_000_000).bps(), 20),
+            Err(ConfigError::BaudRate { requested: (1_000_000).bps(), actual: Bps(500_000) })
+        );
+    }
+
+    #[test]
+    fn zero_baud_rate() {
+        assert_eq!(checked_brr_value((8).mhz(), Bps(0), 20), Err(ConfigError::ZeroBaudRate));
+    }
+}
\ No newline at end of file
@@ -1,11 +1,13 @@
 use embedded_hal::{ blocking, spi };
-use ch32v1::ch32v103::{ RCC, SPI1 };
+use ch32v1::ch32v103::SPI1;
 
 use nb;
 use crate::rcc::*;
 use crate::time::*;
 use crate::gpio::*;
 use crate::gpio::gpioa::{ PA5, PA6, PA7 };
+use crate::gpio::gpiob::{ PB3, PB4, PB5 };
+use crate::afio::{ Afio, Pins, NoRemap, Remap, RemapMode };
 
 pub enum SpiMode {
     Mode0,
@@ -27,69 +29,56 @@ pub enum Error {
     Unkown,
 }
 
-pub trait SckPin<T> {
-    fn remap(&self) -> bool;
-}
-
-pub trait MisoPin<T> {
-    fn remap(&self) -> bool;
-}
-pub trait MosiPin<T> {
-    fn remap(&self) -> bool;
-}
-
-impl SckPin<SPI1> for PA5<AltOutput<PushPull>> {
-    fn remap(&self) -> bool {
-        false
-    }
-}
-
-impl MisoPin<SPI1> for PA6<Input<Floating>> {
-    fn remap(&self) -> bool {
-        false
-    }
-}
+// (SCK, MISO, MOSI)
+impl Pins<SPI1, NoRemap> for (PA5<AltOutput<PushPull>>, PA6<Input<Floating>>, PA7<AltOutput<PushPull>>) {}
 
-impl MosiPin<SPI1> for PA7<AltOutput<PushPull>> {
-    fn remap(&self) -> bool {
-        false
-    }
-}
+// Remap. PB3 and PB4 are usable after Afio::disable_jtag() or disable_swd() released them.
+impl Pins<SPI1, Remap> for (PB3<AltOutput<PushPull>>, PB4<Input<Floating>>, PB5<AltOutput<PushPull>>) {}
 
 pub struct Spi<SPI, PINS> {
     spi: SPI,
     pins: PINS,
+    speed: Hertz,
 }
 
-impl<SCK, MISO, MOSI> Spi<SPI1, (SCK, MISO, MOSI)> {
-    // init USART
-    pub fn spi1(
+// Baud rate control bits to make speed or slower.
+fn br_bits(pclk: Hertz, speed: Hertz) -> u8 {
+    match pclk.0 / speed.0 {
+        0 => unreachable!(),
+        1..=2 => 0b000, // Div2
+        3..=5 => 0b001, // Div4
+        6..=11 => 0b010, //Div8
+        12..=23 => 0b011, // Div16
+        24..=47 => 0b100, // Div32
+        48..=95 => 0b101, // Div64
+        96..=191 => 0b110, // Div128
+        _ => 0b111, // Div256
+    }
+}
+
+impl<PINS> Spi<SPI1, PINS> {
+    // init SPI1
+    // Remap is selected by the pins.
+    pub fn spi1<REMAP>(
         spi: SPI1,
-        pins: (SCK, MISO, MOSI),
+        pins: PINS,
+        afio: &mut Afio,
+        apb2: &mut APB2,
         mode: spi::Mode,
         speed: Hertz,
         clocks: &Clocks
     )
         -> Self
-        where SCK: SckPin<SPI1>, MISO: MisoPin<SPI1>, MOSI: MosiPin<SPI1>
+        where PINS: Pins<SPI1, REMAP>, REMAP: RemapMode
     {
-        unsafe {
-            // provide clock to USART1
-            (*RCC::ptr()).apb2pcenr.modify(|_, w| w.spi1en().set_bit());
+        afio.remap::<SPI1, REMAP>();
+        // provide clock to SPI1
+        SPI1::enable(apb2);
+        SPI1::reset(apb2);
 
+        unsafe {
             // Set SPI1 to Master mode
-            let br_bits = match clocks.pclk2().0 / speed.0 {
-                0 => unreachable!(),
-                1..=2 => 0b000, // Div2
-                3..=5 => 0b001, // Div4
-                6..=11 => 0b010, //Div8
-                12..=23 => 0b011, // Div16
-                24..=47 => 0b100, // Div32
-                48..=95 => 0b101, // Div64
-                96..=191 => 0b110, // Div128
-                _ => 0b111, // Div256
-            };
-            (*SPI1::ptr()).ctlr1.modify(|_, w| w.br().bits(br_bits));
+            (*SPI1::ptr()).ctlr1.modify(|_, w| w.br().bits(br_bits(clocks.pclk2(), speed)));
 
             match mode.polarity {
                 spi::Polarity::IdleLow => {
@@ -131,7 +120,21 @@ impl<SCK, MISO, MOSI> Spi<SPI1, (SCK, MISO, MOSI)> {
             (*SPI1::ptr()).ctlr1.modify(|_, w| w.mstr().set_bit().spe().set_bit());
         }
 
-        Spi { spi, pins }
+        Spi { spi, pins, speed }
+    }
+
+    // Recompute baud rate after CFGR::reconfigure().
+    pub fn reconfigure(&mut self, clocks: &Clocks) {
+        unsafe {
+            // BR can be changed only while SPI is disabled.
+            while (*SPI1::ptr()).statr.read().bsy().bit_is_set() {}
+            let enabled = (*SPI1::ptr()).ctlr1.read().spe().bit_is_set();
+            (*SPI1::ptr()).ctlr1.modify(|_, w| w.spe().clear_bit());
+            (*SPI1::ptr()).ctlr1.modify(|_, w| w.br().bits(br_bits(clocks.pclk2(), self.speed)));
+            if enabled {
+                (*SPI1::ptr()).ctlr1.modify(|_, w| w.spe().set_bit());
+            }
+        }
     }
 
     pub fn enable(&self) {
@@ -147,7 +150,7 @@ impl<SCK, MISO, MOSI> Spi<SPI1, (SCK, MISO, MOSI)> {
     }
 }
 
-impl<SCK, MISO, MOSI> spi::FullDuplex<u8> for Spi<SPI1, (SCK, MISO, MOSI)> {
+impl<PINS> spi::FullDuplex<u8> for Spi<SPI1, PINS> {
     type Error = Error;
 
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
@@ -174,16 +177,10 @@ impl<SCK, MISO, MOSI> spi::FullDuplex<u8> for Spi<SPI1, (SCK, MISO, MOSI)> {
 }
 
 // This trait has default implementation for blocking::spi::Transfer<u8>.
-impl<SCK, MISO, MOSI> blocking::spi::transfer::Default<u8>
-    for Spi<SPI1, (SCK, MISO, MOSI)>
-    where SCK: SckPin<SPI1>, MISO: MisoPin<SPI1>, MOSI: MosiPin<SPI1> {}
+impl<PINS> blocking::spi::transfer::Default<u8> for Spi<SPI1, PINS> {}
 
 // This trait has default implementation for blocking::spi::Write<u8>.
-impl<SCK, MISO, MOSI> blocking::spi::write::Default<u8>
-    for Spi<SPI1, (SCK, MISO, MOSI)>
-    where SCK: SckPin<SPI1>, MISO: MisoPin<SPI1>, MOSI: MosiPin<SPI1> {}
+impl<PINS> blocking::spi::write::Default<u8> for Spi<SPI1, PINS> {}
 
 // This trait has default implementation for blocking::spi::WriteIter<u8>.
-impl<SCK, MISO, MOSI> blocking::spi::write_iter::Default<u8>
-    for Spi<SPI1, (SCK, MISO, MOSI)>
-    where SCK: SckPin<SPI1>, MISO: MisoPin<SPI1>, MOSI: MosiPin<SPI1> {}
\ No newline at end of file
+impl<PINS> blocking::spi::write_iter::Default<u8> for Spi<SPI1, PINS> {}
\ No newline at end of file
@@ -1,9 +1,9 @@
 /// Bits per second
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Bps(pub u32);
 
 /// Hertz
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Hertz(pub u32);
 
 /// Extension trait that adds convenience methods to the `u32` type